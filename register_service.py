@@ -9,6 +9,12 @@ import os
 
 
 def my_local_ip():
+    # Prefer an IPv4 address, but fall back to an IPv6 one on IPv6-only networks.
+    addresses = socket.getaddrinfo(socket.getfqdn(), None)
+    for family in (socket.AF_INET, socket.AF_INET6):
+        for (address_family, _, _, _, sockaddr) in addresses:
+            if address_family == family:
+                return sockaddr[0]
     return socket.gethostbyname(socket.getfqdn())
 
 
@@ -63,6 +69,7 @@ def register_service(
     port: int,
     address: str
 ):
+    host = f"[{address}]" if ":" in address else address
     data = {
         "Name": name,
         "ID": id,
@@ -70,7 +77,7 @@ def register_service(
         "Address": address,
         "Check": {
             "DeregisterCriticalServiceAfter": "15s",
-            "HTTP": f"http://{address}:{port}/health",
+            "HTTP": f"http://{host}:{port}/health",
             "Interval": "10s"
         },
         "Meta": {
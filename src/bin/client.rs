@@ -1,4 +1,4 @@
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 
 use axum::{http::StatusCode, routing::get, Router};
 use clap::Parser;
@@ -7,6 +7,9 @@ use clap::Parser;
 pub struct Args {
     #[clap(short, long, default_value = "9001")]
     port: u16,
+    /// The address to listen on. Use `::` on IPv6-only networks.
+    #[clap(long, default_value = "0.0.0.0")]
+    host: IpAddr,
 }
 
 #[tokio::main]
@@ -22,7 +25,7 @@ pub async fn main() {
         }),
     );
 
-    let addr = SocketAddr::from(([0, 0, 0, 0], args.port));
+    let addr = SocketAddr::new(args.host, args.port);
     tracing::info!("Listening on {}", addr);
     axum::Server::bind(&addr)
         .serve(app.into_make_service())
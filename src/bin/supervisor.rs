@@ -1,15 +1,22 @@
-use std::{net::SocketAddr, sync::Arc};
+use std::{net::SocketAddr, sync::Arc, time::Duration};
 
-use partition_sim::{consul::query_consul_for_peers, Peer, Supervisor};
-use tokio::sync::Mutex;
+use partition_sim::{
+    consul::query_consul_for_peers,
+    events::{read_log, ClusterEvent, ClusterEventKind, EventLog, PeerMapping},
+    nemesis::{Nemesis, NemesisConfig},
+    scenario::Scenario,
+    Firewall, Peer, Supervisor,
+};
+use tokio::sync::{broadcast, Mutex};
 
 use axum::{
     extract::State,
     http::StatusCode,
+    middleware,
     routing::{get, post},
     Json, Router,
 };
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use std::env::var;
 use tower_http::cors::{Any, CorsLayer};
 use uuid::Uuid;
@@ -18,6 +25,49 @@ use uuid::Uuid;
 pub struct Args {
     #[clap(short, long, default_value = "3000")]
     port: u16,
+    /// Check every peer's rules for drift from the applied faults every so many seconds.
+    #[clap(long)]
+    reconcile_interval: Option<u64>,
+    /// When checking for drift, also put back the rules the peers lost.
+    #[clap(long)]
+    repair_drift: bool,
+    /// Retry healing the expired faults that are still applied every so many seconds.
+    #[clap(long, default_value = "10")]
+    heal_interval: u64,
+    /// Append every command run on the peers to this JSON Lines file.
+    #[clap(long)]
+    event_log: Option<std::path::PathBuf>,
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Load the cluster, run the scenario in the given YAML or TOML file and exit,
+    /// instead of serving the API.
+    RunScenario { path: std::path::PathBuf },
+    /// Load the cluster and keep applying random faults until interrupted,
+    /// instead of serving the API.
+    Nemesis {
+        /// A YAML (or JSON) file with the nemesis config. The defaults are used otherwise.
+        #[clap(long)]
+        config: Option<std::path::PathBuf>,
+        /// Replay the faults of an earlier run. Overrides the seed in the config.
+        #[clap(long)]
+        seed: Option<u64>,
+    },
+    /// Load the cluster, run the commands in an event log again and exit,
+    /// instead of serving the API.
+    Replay {
+        path: std::path::PathBuf,
+        /// How to tell which peer each logged command goes to: `position` (with the
+        /// peers ordered by address) or `ip`.
+        #[clap(long, default_value = "position")]
+        map_by: PeerMapping,
+        /// Run the commands back to back, instead of as far apart as they were logged.
+        #[clap(long)]
+        no_wait: bool,
+    },
 }
 
 #[derive(Debug)]
@@ -27,30 +77,182 @@ pub struct AppState {
     pub consul_port: u16,
     pub peer_port: u16,
     pub service_name: String,
+    /// The firewall to use on every peer, or `None` to detect it per peer.
+    pub firewall: Option<Firewall>,
+    /// The running nemesis, if any.
+    pub nemesis: Option<tokio::task::JoinHandle<()>>,
+    /// Where the supervisor records the commands it runs, if anywhere.
+    pub event_log: Option<std::path::PathBuf>,
+    /// Where the supervisor announces the faults, kept across cluster reloads so that
+    /// followers stay subscribed.
+    pub cluster_events: broadcast::Sender<ClusterEvent>,
 }
 
 impl AppState {
     pub fn new(supervisor: Supervisor) -> Self {
         let consul_addr = std::env::var("CONSUL_ADDR").unwrap_or_else(|_| "127.0.0.1".into());
+        let firewall = match std::env::var("FIREWALL") {
+            Ok(firewall) if firewall != "auto" => Some(
+                firewall
+                    .parse()
+                    .expect("FIREWALL must be one of: auto, iptables, nftables"),
+            ),
+            _ => None,
+        };
+        let (cluster_events, _) = broadcast::channel(1024);
         Self {
-            supervisor,
+            supervisor: supervisor.with_cluster_events(cluster_events.clone()),
             consul_addr,
             consul_port: 8600,
             service_name: "test-node".into(),
             peer_port: 0,
+            firewall,
+            nemesis: None,
+            event_log: None,
+            cluster_events,
         }
     }
 }
 
 pub type SharedState<T = AppState> = Arc<Mutex<T>>;
 
+/// Heal the faults whose time is up. The ones that can't be healed get logged
+/// (and announced) by the supervisor, and retried by the next sweep.
+async fn heal_expired(state: &SharedState) {
+    let (healed, _) = state.lock().await.supervisor.heal_expired().await;
+    for fault in healed {
+        tracing::debug!("Healed the expired fault {:?}.", fault);
+    }
+}
+
+/// Heal the faults whose time is up once the `duration` (if any) has passed.
+/// Faults that got applied again in the meantime, with a longer duration or none,
+/// are left alone until their own time is up.
+pub fn heal_after(state: SharedState, duration: Option<Duration>) {
+    let Some(duration) = duration else {
+        return;
+    };
+    tokio::spawn(async move {
+        tokio::time::sleep(duration).await;
+        heal_expired(&state).await;
+    });
+}
+
+/// Heal the expired faults every `interval`, so that the ones that couldn't be healed
+/// on time (e.g. because their peer was down) get retried until they are.
+pub async fn sweep_expired(state: SharedState, interval: Duration) {
+    let mut interval = tokio::time::interval(interval);
+    loop {
+        interval.tick().await;
+        heal_expired(&state).await;
+    }
+}
+
 #[tokio::main]
 pub async fn main() {
     tracing_subscriber::fmt::init();
     let args = Args::parse();
 
-    let state = Arc::new(Mutex::new(AppState::new(Supervisor::default())));
+    let mut app_state = AppState::new(Supervisor::default());
+    app_state.event_log = args.event_log;
+    let state = Arc::new(Mutex::new(app_state));
+
+    if let Some(Command::RunScenario { path }) = args.command {
+        let scenario = Scenario::from_path(&path).expect("Failed to load the scenario");
+        let peers =
+            cluster_api::load_peers(&mut *state.lock().await).expect("Failed to load the cluster");
+        tracing::info!("Loaded {} peers.", peers.len());
+        if let Err(err) = scenario_api::run(state, scenario).await {
+            tracing::error!("The scenario failed: {}", err);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(Command::Replay {
+        path,
+        map_by,
+        no_wait,
+    }) = args.command
+    {
+        let events = read_log(&path).expect("Failed to load the event log");
+        let mut guard = state.lock().await;
+        let peers = cluster_api::load_peers(&mut guard).expect("Failed to load the cluster");
+        tracing::info!(
+            "Loaded {} peers. Replaying {} events.",
+            peers.len(),
+            events.len()
+        );
+        let mut previous = None;
+        for event in events {
+            if let (false, Some(previous)) = (no_wait, previous) {
+                let gap = event.timestamp.duration_since(previous).unwrap_or_default();
+                tokio::time::sleep(gap).await;
+            }
+            previous = Some(event.timestamp);
+            match guard.supervisor.replay(&event, map_by).await {
+                Ok(output) if output.status.code() != event.exit_status => tracing::warn!(
+                    "{:?} on {} exited with {:?} instead of {:?}.",
+                    event.command,
+                    event.peer_ip,
+                    output.status.code(),
+                    event.exit_status
+                ),
+                Ok(_) => tracing::info!("Replayed {:?} on {}.", event.command, event.peer_ip),
+                Err(err) => {
+                    tracing::error!("Couldn't replay {:?}: {}", event, err);
+                    std::process::exit(1);
+                }
+            }
+        }
+        return;
+    }
+
+    if let Some(Command::Nemesis { config, seed }) = args.command {
+        let mut config: NemesisConfig = match config {
+            Some(path) => serde_yaml::from_str(
+                &std::fs::read_to_string(path).expect("Failed to read the nemesis config"),
+            )
+            .expect("Failed to parse the nemesis config"),
+            None => NemesisConfig::default(),
+        };
+        config.seed = seed.or(config.seed);
+        let nemesis = Nemesis::new(config).expect("Invalid nemesis config");
+        let peer_count = {
+            let mut guard = state.lock().await;
+            cluster_api::load_peers(&mut guard).expect("Failed to load the cluster");
+            nemesis_api::peer_count(&guard.supervisor)
+        };
+        let peer_count = match peer_count {
+            Ok(peer_count) => peer_count,
+            Err(err) => {
+                tracing::error!("Can't start the nemesis: {}", err);
+                std::process::exit(1);
+            }
+        };
+        tracing::info!(
+            "Loaded {} peers. Starting the nemesis with seed {}.",
+            peer_count,
+            nemesis.seed()
+        );
+        nemesis_api::run(state, nemesis, peer_count).await;
+        return;
+    }
+
+    tokio::spawn(sweep_expired(
+        state.clone(),
+        Duration::from_secs(args.heal_interval.max(1)),
+    ));
 
+    if let Some(interval) = args.reconcile_interval {
+        tokio::spawn(drift_api::watch(
+            state.clone(),
+            std::time::Duration::from_secs(interval),
+            args.repair_drift,
+        ));
+    }
+
+    let cluster_events = state.lock().await.cluster_events.clone();
     let cors = CorsLayer::new()
         .allow_methods(Any)
         .allow_headers(Any)
@@ -68,11 +270,48 @@ pub async fn main() {
             "/partition/:peer_id/:target_peer_id",
             post(partition_api::partition),
         )
+        .route(
+            "/partition/groups",
+            post(partition_api::partition_groups).delete(partition_api::heal_groups),
+        )
+        .route("/partition/shape", post(partition_api::partition_shape))
+        .route("/isolate/:peer_id", post(partition_api::isolate))
+        .route("/rejoin/:peer_id", post(partition_api::rejoin))
         .route("/heal/:peer_id/:target_peer_id", post(partition_api::heal))
         .route("/rules/:peer_id", get(partition_api::rules))
+        .route(
+            "/delay/:peer_id/:target_peer_id",
+            post(shaping_api::delay).delete(shaping_api::clear_netem),
+        )
+        .route(
+            "/netem/:peer_id/:target_peer_id",
+            post(shaping_api::netem).delete(shaping_api::clear_netem),
+        )
+        .route(
+            "/throttle/:peer_id/:target_peer_id",
+            post(shaping_api::throttle).delete(shaping_api::clear_throttle),
+        )
         .route("/restore", get(partition_api::restore))
+        .route("/topology", get(partition_api::topology))
+        .route("/faults", get(partition_api::faults))
+        .route("/drift", get(drift_api::check_drift))
+        .route("/reconcile", post(drift_api::reconcile))
+        .route("/scenarios", post(scenario_api::start))
+        .route(
+            "/nemesis",
+            post(nemesis_api::start).delete(nemesis_api::stop),
+        )
+        .route("/nemesis/plan", post(nemesis_api::plan))
+        .route("/snapshots", post(snapshot_api::take_snapshot))
+        .route("/snapshots/:name", get(snapshot_api::get_snapshot))
+        .route("/snapshots/:name/apply", post(snapshot_api::apply_snapshot))
         .route("/load_cluster", get(cluster_api::load_cluster))
         .route("/cluster", get(cluster_api::get_cluster))
+        .route("/events", get(events_api::follow))
+        .layer(middleware::from_fn_with_state(
+            cluster_events,
+            events_api::report_errors,
+        ))
         .layer(cors)
         .with_state(state);
 
@@ -110,33 +349,38 @@ mod cluster_api {
         pub address: String,
     }
 
-    #[axum_macros::debug_handler]
-    pub async fn load_cluster(
-        State(state): State<SharedState>,
-    ) -> partition_sim::Result<Json<Vec<PeerInfo>>> {
-        tracing::debug!("load_cluster: {:?}", state);
-        let mut guard = state.lock().await;
-
+    /// Look the peers up in Consul and supervise them from now on.
+    pub fn load_peers(state: &mut AppState) -> partition_sim::Result<Vec<PeerInfo>> {
         let home = var("HOME").unwrap_or_else(|_| "/root".into());
         let pub_path = format!("{}/.ssh/id_ed25519.pub", home);
         let priv_path = format!("{}/.ssh/id_ed25519", home);
 
         if let Ok(peers) =
-            query_consul_for_peers(&guard.consul_addr, guard.consul_port, &guard.service_name)
+            query_consul_for_peers(&state.consul_addr, state.consul_port, &state.service_name)
         {
             if let Some(first) = peers.first() {
-                guard.peer_port = first.1;
+                state.peer_port = first.1;
             }
             let peers: Vec<_> = peers
                 .into_iter()
-                .map(|p| Peer::new(p.0, Some("root"), Some(&priv_path)))
+                .map(|p| {
+                    Peer::new(p.0, Some("root"), Some(&priv_path)).with_firewall(state.firewall)
+                })
                 .collect();
 
             tracing::info!("Loaded {} peers: {:?}", peers.len(), peers);
 
-            guard.supervisor = Supervisor::new(peers).with_key(&pub_path);
-            guard.supervisor.set_up_ssh()?;
-            let peer_id_strings: Vec<_> = guard
+            // Carry the faults over, so that the pending ones still get healed on time.
+            let mut supervisor = Supervisor::new(peers)
+                .with_key(&pub_path)
+                .with_cluster_events(state.cluster_events.clone())
+                .with_faults_of(&state.supervisor);
+            if let Some(path) = &state.event_log {
+                supervisor = supervisor.with_event_log(EventLog::open(path)?);
+            }
+            state.supervisor = supervisor;
+            state.supervisor.set_up_ssh()?;
+            let peer_id_strings: Vec<_> = state
                 .supervisor
                 .get_peer_ids()
                 .iter()
@@ -147,12 +391,21 @@ mod cluster_api {
             for peer_id in peer_id_strings.iter() {
                 hmap.insert(
                     peer_id.clone(),
-                    guard
+                    state
                         .supervisor
                         .get_peer(Uuid::parse_str(peer_id)?)?
                         .ip_addr,
                 );
             }
+            let peers = state
+                .supervisor
+                .get_peer_ids()
+                .iter()
+                .map(|peer_id| Ok((*peer_id, state.supervisor.get_peer(*peer_id)?.ip_addr)))
+                .collect::<partition_sim::Result<_>>()?;
+            state
+                .supervisor
+                .notify(ClusterEventKind::ClusterLoaded { peers });
             let mut to_output = Vec::new();
             for (node_uuid, node_address) in hmap.iter() {
                 to_output.push(PeerInfo {
@@ -160,11 +413,20 @@ mod cluster_api {
                     address: node_address.to_string(),
                 });
             }
-            Ok(to_output.into())
+            Ok(to_output)
         } else {
             Err(partition_sim::Error::Other("Failed to load cluster".into()))
         }
     }
+
+    #[axum_macros::debug_handler]
+    pub async fn load_cluster(
+        State(state): State<SharedState>,
+    ) -> partition_sim::Result<Json<Vec<PeerInfo>>> {
+        tracing::debug!("load_cluster: {:?}", state);
+        let mut guard = state.lock().await;
+        Ok(load_peers(&mut guard)?.into())
+    }
 }
 
 /// The Partition API as described in [this paper].
@@ -173,123 +435,903 @@ mod cluster_api {
 /// [stuff]: https://www.scs.stanford.edu/14au-cs244b/labs/projects/RaftMonkey-Chakoumakos-Trusheim-revised.pdf
 mod partition_api {
     use super::*;
-    use axum::extract::Path;
+    use axum::extract::{Path, Query};
+    use partition_sim::commands::{Action, Direction, PortRange, Protocol};
+    use partition_sim::faults::{Fault, HealOptions, Partition};
+    use partition_sim::rules::Rule;
+    use partition_sim::topology::{Shape, Topology};
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Serialize, Deserialize, Debug, Default)]
+    pub struct PartitionParams {
+        /// Only drop each packet with this probability (between 0 and 1)
+        /// instead of dropping all of them.
+        pub probability: Option<f64>,
+        /// Which way to cut the traffic between the source and the target peer.
+        pub direction: Option<Direction>,
+        /// Only cut the traffic of this protocol.
+        pub protocol: Option<Protocol>,
+        /// Only cut the traffic to this destination port (or range of ports, like `9000:9010`).
+        /// Requires the `tcp` or `udp` protocol.
+        pub port: Option<PortRange>,
+        /// Whether the packets should vanish (`drop`) or get rejected (`reject`).
+        pub action: Option<Action>,
+        /// Also make the target peer forget the connections it tracks from/to the source peer,
+        /// so that the partition reliably tears down the established ones.
+        #[serde(default)]
+        pub flush_conntrack: bool,
+        /// Heal the partition on its own after this long, like `30s` or `5m`. Ignored when healing.
+        #[serde(default, with = "humantime_serde")]
+        pub duration: Option<Duration>,
+    }
+
+    impl PartitionParams {
+        pub fn partition(&self) -> Partition {
+            Partition {
+                probability: self.probability,
+                direction: self.direction.unwrap_or_default(),
+                protocol: self.protocol,
+                port: self.port,
+                action: self.action.unwrap_or_default(),
+            }
+        }
+
+        /// The partition to heal, unless none of its options were given.
+        pub fn given_partition(&self) -> Option<Partition> {
+            HealOptions {
+                probability: self.probability,
+                direction: self.direction,
+                protocol: self.protocol,
+                port: self.port,
+                action: self.action,
+            }
+            .partition()
+        }
+    }
+
+    #[derive(Serialize, Deserialize, Debug)]
+    pub struct Changes {
+        /// Whether any rule was added or deleted.
+        pub changed: bool,
+    }
 
     /// Partition the network between two peers.
-    /// Ask the target peer to drop all packets from the source peer.
+    /// Ask the target peer to drop all packets from the source peer,
+    /// or only a random fraction of them if a `probability` is given.
+    /// With an `outbound` (or `both`) `direction`, the target peer
+    /// also drops all packets it sends to the source peer.
+    /// A `protocol` and `port` narrow the partition down to that traffic only.
+    /// With the `reject` action, the packets get rejected instead so the source fails fast.
+    /// With `flush_conntrack`, the target peer also forgets the connections it tracks from/to
+    /// the source peer, which tears down the established ones.
+    /// Partitioning twice is a no-op, which the response reports as unchanged.
     pub async fn partition(
         Path(path): Path<(String, String)>,
+        Query(params): Query<PartitionParams>,
         State(state): State<SharedState>,
-    ) -> partition_sim::Result<String> {
+    ) -> partition_sim::Result<Json<Changes>> {
         let source_peer_id =
             Uuid::parse_str(&path.0).map_err(partition_sim::Error::UuidParseError)?;
         let target_peer_id =
             Uuid::parse_str(&path.1).map_err(partition_sim::Error::UuidParseError)?;
         let mut guard = state.lock().await;
 
-        let source_peer = guard.supervisor.get_peer(source_peer_id)?;
-        let ip_addr = source_peer.ip_addr;
-
-        let output = guard
+        let changed = guard
             .supervisor
-            .execute(
+            .partition(
+                source_peer_id,
                 target_peer_id,
-                partition_sim::commands::IpTablesCommands::DropFrom { source_ip: ip_addr },
+                params.partition(),
+                params.duration,
             )
             .await?;
+        // Schedule the heal right away, so that the partition still heals if flushing fails.
+        heal_after(state.clone(), params.duration);
+        if params.flush_conntrack {
+            guard
+                .supervisor
+                .flush_conntrack(source_peer_id, target_peer_id, params.partition())
+                .await?;
+        }
 
-        if output.status.success() {
-            tracing::debug!(
-                "Partitioned {0} (ip: {2}) from {1} (ip: {3}) so that {2} can't reach {3}.",
-                source_peer_id,
-                target_peer_id,
-                ip_addr,
-                guard.supervisor.get_peer(target_peer_id)?.ip_addr
-            );
-            Ok(String::from_utf8_lossy(&output.stdout).to_string())
-        } else {
-            Err(output.into())
+        tracing::debug!(
+            "Partitioned {0} (ip: {2}) from {1} (ip: {3}) ({4:?} to {3}).",
+            source_peer_id,
+            target_peer_id,
+            guard.supervisor.get_peer(source_peer_id)?.ip_addr,
+            guard.supervisor.get_peer(target_peer_id)?.ip_addr,
+            params.partition().direction,
+        );
+        Ok(Json(Changes { changed }))
+    }
+
+    #[derive(Serialize, Deserialize, Debug)]
+    pub struct GroupsRequest {
+        /// Two or more disjoint groups of peers.
+        pub groups: Vec<Vec<Uuid>>,
+        pub probability: Option<f64>,
+        pub protocol: Option<Protocol>,
+        pub port: Option<PortRange>,
+        #[serde(default)]
+        pub action: Action,
+        #[serde(default)]
+        pub flush_conntrack: bool,
+        /// Heal the split on its own after this long, like `30s` or `5m`. Ignored when healing.
+        #[serde(default, with = "humantime_serde")]
+        pub duration: Option<Duration>,
+    }
+
+    impl GroupsRequest {
+        pub fn partition(&self) -> Partition {
+            Partition {
+                probability: self.probability,
+                direction: Direction::Both,
+                protocol: self.protocol,
+                port: self.port,
+                action: self.action,
+            }
+        }
+    }
+
+    /// Split the cluster into groups of peers that can only talk within their group.
+    /// Every link between peers of different groups gets cut both ways, with a single
+    /// round trip per peer. Peers left out of every group are left alone.
+    pub async fn partition_groups(
+        State(state): State<SharedState>,
+        Json(request): Json<GroupsRequest>,
+    ) -> partition_sim::Result<Json<Changes>> {
+        let mut guard = state.lock().await;
+        let changed = guard
+            .supervisor
+            .partition_groups(&request.groups, request.partition(), request.duration)
+            .await?;
+        heal_after(state.clone(), request.duration);
+        if request.flush_conntrack {
+            for (source_peer_id, target_peer_id) in
+                guard.supervisor.cross_group_pairs(&request.groups)?
+            {
+                guard
+                    .supervisor
+                    .flush_conntrack(source_peer_id, target_peer_id, request.partition())
+                    .await?;
+            }
         }
+        tracing::debug!("Partitioned the cluster into {:?}.", request.groups);
+        Ok(Json(Changes { changed }))
+    }
+
+    #[derive(Serialize, Deserialize, Debug)]
+    pub struct ShapeRequest {
+        #[serde(flatten)]
+        pub shape: Shape,
+        /// Makes the random choices of the shape repeatable.
+        pub seed: Option<u64>,
+        #[serde(default)]
+        pub action: Action,
+        /// Heal the shape on its own after this long, like `30s` or `5m`.
+        #[serde(default, with = "humantime_serde")]
+        pub duration: Option<Duration>,
+    }
+
+    #[derive(Serialize, Deserialize, Debug)]
+    pub struct ShapeResponse {
+        /// The pairs of peers whose links got cut, both ways.
+        pub cuts: Vec<(Uuid, Uuid)>,
+    }
+
+    /// Give the cluster a well-known partition shape (random halves, majority/minority,
+    /// bridge, ring or star) in one call.
+    pub async fn partition_shape(
+        State(state): State<SharedState>,
+        Json(request): Json<ShapeRequest>,
+    ) -> partition_sim::Result<Json<ShapeResponse>> {
+        let partition = Partition {
+            action: request.action,
+            ..Default::default()
+        };
+        let mut guard = state.lock().await;
+        let cuts = guard
+            .supervisor
+            .apply_shape(&request.shape, partition, request.seed, request.duration)
+            .await?;
+        tracing::debug!(
+            "Gave the cluster the shape {:?}: cut {:?}.",
+            request.shape,
+            cuts
+        );
+        heal_after(state.clone(), request.duration);
+        Ok(Json(ShapeResponse { cuts }))
+    }
+
+    /// Heal the links cut by partitioning the cluster into the same groups.
+    pub async fn heal_groups(
+        State(state): State<SharedState>,
+        Json(request): Json<GroupsRequest>,
+    ) -> partition_sim::Result<Json<Changes>> {
+        let mut guard = state.lock().await;
+        let changed = guard
+            .supervisor
+            .heal_groups(&request.groups, request.partition())
+            .await?;
+        tracing::debug!("Healed the partition into {:?}.", request.groups);
+        Ok(Json(Changes { changed }))
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Default)]
+    pub struct IsolateParams {
+        /// Whether the packets should vanish (`drop`) or get rejected (`reject`).
+        pub action: Option<Action>,
+        /// Also make the peer forget the connections it tracks from/to the other peers.
+        /// Ignored when rejoining.
+        #[serde(default)]
+        pub flush_conntrack: bool,
+        /// Rejoin the peer on its own after this long, like `30s` or `5m`. Ignored when rejoining.
+        #[serde(default, with = "humantime_serde")]
+        pub duration: Option<Duration>,
+    }
+
+    impl IsolateParams {
+        pub fn partition(&self) -> Partition {
+            Partition {
+                direction: Direction::Both,
+                action: self.action.unwrap_or_default(),
+                ..Default::default()
+            }
+        }
+
+        /// The isolation to undo, unless its `action` wasn't given.
+        pub fn given_partition(&self) -> Option<Partition> {
+            self.action.map(|_| self.partition())
+        }
+    }
+
+    /// Cut a peer off from every other peer, both ways. All the rules go on the isolated
+    /// peer, in a single round trip.
+    pub async fn isolate(
+        Path(path): Path<String>,
+        Query(params): Query<IsolateParams>,
+        State(state): State<SharedState>,
+    ) -> partition_sim::Result<Json<Changes>> {
+        let peer_id = Uuid::parse_str(&path).map_err(partition_sim::Error::UuidParseError)?;
+        let mut guard = state.lock().await;
+        let changed = guard
+            .supervisor
+            .isolate(peer_id, params.partition(), params.duration)
+            .await?;
+        heal_after(state.clone(), params.duration);
+        if params.flush_conntrack {
+            for other_peer_id in guard.supervisor.get_peer_ids().to_vec() {
+                if other_peer_id != peer_id {
+                    guard
+                        .supervisor
+                        .flush_conntrack(other_peer_id, peer_id, params.partition())
+                        .await?;
+                }
+            }
+        }
+        tracing::debug!(
+            "Isolated {0} (ip: {1}).",
+            peer_id,
+            guard.supervisor.get_peer(peer_id)?.ip_addr
+        );
+        Ok(Json(Changes { changed }))
+    }
+
+    /// Undo the isolation of a peer. The `action` must match the one given when isolating.
+    /// Without an `action`, every partition recorded between the peer and the others is healed.
+    pub async fn rejoin(
+        Path(path): Path<String>,
+        Query(params): Query<IsolateParams>,
+        State(state): State<SharedState>,
+    ) -> partition_sim::Result<Json<Changes>> {
+        let peer_id = Uuid::parse_str(&path).map_err(partition_sim::Error::UuidParseError)?;
+        let mut guard = state.lock().await;
+        let changed = guard
+            .supervisor
+            .rejoin(peer_id, params.given_partition())
+            .await?;
+        tracing::debug!(
+            "Rejoined {0} (ip: {1}).",
+            peer_id,
+            guard.supervisor.get_peer(peer_id)?.ip_addr
+        );
+        Ok(Json(Changes { changed }))
     }
 
     /// Heal the network between two peers.
     /// Ask the target peer to delete "drop all incoming packets" rules from the source peer.
+    /// The `probability`, `direction`, `protocol`, `port` and `action` must match the ones given when partitioning.
+    /// Without any of them, every partition recorded between the peers is healed.
+    /// All copies of a matching rule are deleted, and healing twice is a no-op.
     pub async fn heal(
         Path(path): Path<(String, String)>,
+        Query(params): Query<PartitionParams>,
         State(state): State<SharedState>,
-    ) -> partition_sim::Result<String> {
+    ) -> partition_sim::Result<Json<Changes>> {
         let source_peer_id =
             Uuid::parse_str(&path.0).map_err(partition_sim::Error::UuidParseError)?;
         let target_peer_id =
             Uuid::parse_str(&path.1).map_err(partition_sim::Error::UuidParseError)?;
         let mut guard = state.lock().await;
 
-        let source_peer = guard.supervisor.get_peer(source_peer_id)?;
-        let ip_addr = source_peer.ip_addr;
-
-        let output = guard
+        let changed = guard
             .supervisor
-            .execute(
-                target_peer_id,
-                partition_sim::commands::IpTablesCommands::RestoreFrom { source_ip: ip_addr },
-            )
+            .heal(source_peer_id, target_peer_id, params.given_partition())
             .await?;
 
-        if output.status.success() {
-            tracing::debug!(
-                "Healed the connection of {0} (ip: {2}) from {1} (ip: {3}) so that {2} can reach {3}.",
-                source_peer_id,
-                target_peer_id,
-                ip_addr,
-                guard.supervisor.get_peer(target_peer_id)?.ip_addr
-            );
-            Ok(String::from_utf8_lossy(&output.stdout).to_string())
-        } else {
-            Err(output.into())
-        }
+        tracing::debug!(
+            "Healed the connection of {0} (ip: {2}) from {1} (ip: {3}).",
+            source_peer_id,
+            target_peer_id,
+            guard.supervisor.get_peer(source_peer_id)?.ip_addr,
+            guard.supervisor.get_peer(target_peer_id)?.ip_addr,
+        );
+        Ok(Json(Changes { changed }))
     }
 
-    /// Get the iptables rules for a peer.
+    /// Get the firewall rules for a peer, with their addresses resolved to peers.
     pub async fn rules(
         Path(path): Path<String>,
         State(state): State<SharedState>,
-    ) -> partition_sim::Result<String> {
+    ) -> partition_sim::Result<Json<Vec<Rule>>> {
         let source_peer_id =
             Uuid::parse_str(&path).map_err(partition_sim::Error::UuidParseError)?;
         let mut guard = state.lock().await;
-        let output = guard
+        let rules = guard.supervisor.get_rules(source_peer_id).await?;
+        tracing::debug!(
+            "Retrieved {0} firewall rules for {1} (ip: {2}).",
+            rules.len(),
+            source_peer_id,
+            guard.supervisor.get_peer(source_peer_id)?.ip_addr,
+        );
+        Ok(Json(rules))
+    }
+
+    /// Get the reachability between every ordered pair of peers in the cluster.
+    pub async fn topology(
+        State(state): State<SharedState>,
+    ) -> partition_sim::Result<Json<Topology>> {
+        let mut guard = state.lock().await;
+        Ok(Json(guard.supervisor.topology().await?))
+    }
+
+    /// Restore all peers to a clean state.
+    /// This will delete all the iptables rules and traffic shaping we added
+    /// and restore the full network to a healthy state.
+    pub async fn restore(State(state): State<SharedState>) -> partition_sim::Result<()> {
+        let mut guard = state.lock().await;
+        guard.supervisor.restore().await?;
+        tracing::debug!(
+            "Restored all the iptables rules and traffic shaping. Network should be healthy now."
+        );
+        Ok(())
+    }
+
+    /// List the faults the supervisor has applied, in the order it applied them.
+    pub async fn faults(
+        State(state): State<SharedState>,
+    ) -> partition_sim::Result<Json<Vec<Fault>>> {
+        let guard = state.lock().await;
+        Ok(Json(guard.supervisor.faults().to_vec()))
+    }
+}
+
+/// Degrade (rather than cut) the network between two peers
+/// by shaping the traffic with `tc`.
+mod shaping_api {
+    use super::*;
+    use axum::extract::Path;
+    use partition_sim::commands::Netem;
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Serialize, Deserialize, Debug)]
+    pub struct Delay {
+        pub delay_ms: u32,
+        #[serde(default)]
+        pub jitter_ms: u32,
+        /// Clear the delay on its own after this long, like `30s` or `5m`.
+        #[serde(default, with = "humantime_serde")]
+        pub duration: Option<Duration>,
+    }
+
+    #[derive(Serialize, Deserialize, Debug)]
+    pub struct NetemRequest {
+        #[serde(flatten)]
+        pub netem: Netem,
+        /// Clear the impairments on their own after this long, like `30s` or `5m`.
+        #[serde(default, with = "humantime_serde")]
+        pub duration: Option<Duration>,
+    }
+
+    #[derive(Serialize, Deserialize, Debug)]
+    pub struct Throttle {
+        pub rate_kbit: u32,
+        /// Lift the cap on its own after this long, like `30s` or `5m`.
+        #[serde(default, with = "humantime_serde")]
+        pub duration: Option<Duration>,
+    }
+
+    fn parse_path(path: &(String, String)) -> partition_sim::Result<(Uuid, Uuid)> {
+        let source_peer_id =
+            Uuid::parse_str(&path.0).map_err(partition_sim::Error::UuidParseError)?;
+        let target_peer_id =
+            Uuid::parse_str(&path.1).map_err(partition_sim::Error::UuidParseError)?;
+        Ok((source_peer_id, target_peer_id))
+    }
+
+    /// Slow down the network between two peers.
+    /// Ask the target peer to delay all packets it sends to the source peer.
+    pub async fn delay(
+        Path(path): Path<(String, String)>,
+        State(state): State<SharedState>,
+        Json(delay): Json<Delay>,
+    ) -> partition_sim::Result<()> {
+        let (source_peer_id, target_peer_id) = parse_path(&path)?;
+        let netem = Netem {
+            delay_ms: delay.delay_ms,
+            jitter_ms: delay.jitter_ms,
+            ..Default::default()
+        };
+        let mut guard = state.lock().await;
+        guard
             .supervisor
-            .execute(
+            .netem(source_peer_id, target_peer_id, netem, delay.duration)
+            .await?;
+        tracing::debug!(
+            "Delayed the traffic from {1} to {0} by {2:?}.",
+            source_peer_id,
+            target_peer_id,
+            delay,
+        );
+        heal_after(state.clone(), delay.duration);
+        Ok(())
+    }
+
+    /// Impair the network between two peers.
+    /// Ask the target peer to delay, duplicate, reorder and/or corrupt
+    /// the packets it sends to the source peer.
+    pub async fn netem(
+        Path(path): Path<(String, String)>,
+        State(state): State<SharedState>,
+        Json(request): Json<NetemRequest>,
+    ) -> partition_sim::Result<()> {
+        let (source_peer_id, target_peer_id) = parse_path(&path)?;
+        let mut guard = state.lock().await;
+        guard
+            .supervisor
+            .netem(
                 source_peer_id,
-                partition_sim::commands::IpTablesCommands::Get,
+                target_peer_id,
+                request.netem.clone(),
+                request.duration,
             )
             .await?;
+        tracing::debug!(
+            "Impaired the traffic from {1} to {0} with {2:?}.",
+            source_peer_id,
+            target_peer_id,
+            request.netem,
+        );
+        heal_after(state.clone(), request.duration);
+        Ok(())
+    }
+
+    /// Remove any delay (or other impairment) between two peers.
+    /// Ask the target peer to stop impairing the packets it sends to the source peer.
+    pub async fn clear_netem(
+        Path(path): Path<(String, String)>,
+        State(state): State<SharedState>,
+    ) -> partition_sim::Result<()> {
+        let (source_peer_id, target_peer_id) = parse_path(&path)?;
+        let mut guard = state.lock().await;
+        guard
+            .supervisor
+            .clear_netem(source_peer_id, target_peer_id)
+            .await
+    }
 
-        if output.status.success() {
-            tracing::debug!(
-                "Retrieved INPUT iptables rules for {0} (ip: {1}).",
+    /// Cap the bandwidth between two peers.
+    /// Ask the target peer to limit the rate at which it sends packets to the source peer.
+    pub async fn throttle(
+        Path(path): Path<(String, String)>,
+        State(state): State<SharedState>,
+        Json(throttle): Json<Throttle>,
+    ) -> partition_sim::Result<()> {
+        let (source_peer_id, target_peer_id) = parse_path(&path)?;
+        let mut guard = state.lock().await;
+        guard
+            .supervisor
+            .throttle(
                 source_peer_id,
-                guard.supervisor.get_peer(source_peer_id)?.ip_addr,
+                target_peer_id,
+                throttle.rate_kbit,
+                throttle.duration,
+            )
+            .await?;
+        tracing::debug!(
+            "Throttled the traffic from {1} to {0} at {2} kbit/s.",
+            source_peer_id,
+            target_peer_id,
+            throttle.rate_kbit,
+        );
+        heal_after(state.clone(), throttle.duration);
+        Ok(())
+    }
+
+    /// Remove any bandwidth cap between two peers.
+    /// Ask the target peer to stop limiting the rate at which it sends packets to the source peer.
+    pub async fn clear_throttle(
+        Path(path): Path<(String, String)>,
+        State(state): State<SharedState>,
+    ) -> partition_sim::Result<()> {
+        let (source_peer_id, target_peer_id) = parse_path(&path)?;
+        let mut guard = state.lock().await;
+        guard
+            .supervisor
+            .clear_throttle(source_peer_id, target_peer_id)
+            .await
+    }
+}
+
+/// Notice when the rules on the peers no longer match the faults the supervisor applied,
+/// e.g. because a container restarted and came back healed.
+mod drift_api {
+    use super::*;
+    use partition_sim::drift::Drift;
+
+    /// Report the peers whose rules drifted from the applied faults.
+    pub async fn check_drift(
+        State(state): State<SharedState>,
+    ) -> partition_sim::Result<Json<Vec<Drift>>> {
+        let mut guard = state.lock().await;
+        Ok(Json(guard.supervisor.check_drift().await?))
+    }
+
+    /// Put back the rules the peers lost, and report the drift found before doing so.
+    pub async fn reconcile(
+        State(state): State<SharedState>,
+    ) -> partition_sim::Result<Json<Vec<Drift>>> {
+        let mut guard = state.lock().await;
+        Ok(Json(guard.supervisor.reconcile().await?))
+    }
+
+    /// Check for drift forever, at the given interval, and optionally repair it. The state
+    /// is only locked to capture what to check, so the API stays usable while the checks
+    /// wait on SSH.
+    pub async fn watch(state: SharedState, interval: std::time::Duration, repair: bool) {
+        let mut interval = tokio::time::interval(interval);
+        loop {
+            interval.tick().await;
+            let checks = state.lock().await.supervisor.drift_checks();
+            let checks = match checks {
+                Ok(checks) => checks,
+                Err(err) => {
+                    report_error(
+                        &state,
+                        &format!("couldn't check the rules for drift: {}", err),
+                    )
+                    .await;
+                    continue;
+                }
+            };
+            for check in checks {
+                let peer_id = check.peer_id();
+                let drift = match check.run().await {
+                    // The faults may have changed since, so check again before repairing.
+                    Ok(drift) if repair && !drift.missing.is_empty() => {
+                        state.lock().await.supervisor.reconcile_peer(peer_id).await
+                    }
+                    result => result,
+                };
+                match drift {
+                    Ok(drift) if drift.is_empty() => {}
+                    Ok(drift) => {
+                        tracing::warn!(
+                            "Rules on {0} drifted: {1} missing{2}, {3} unexpected: {4:?}",
+                            drift.peer,
+                            drift.missing.len(),
+                            if repair && !drift.missing.is_empty() {
+                                " (reapplied)"
+                            } else {
+                                ""
+                            },
+                            drift.unexpected.len(),
+                            drift,
+                        );
+                    }
+                    Err(err) => {
+                        report_error(
+                            &state,
+                            &format!("couldn't check the rules on {} for drift: {}", peer_id, err),
+                        )
+                        .await;
+                    }
+                }
+            }
+        }
+    }
+
+    async fn report_error(state: &SharedState, message: &str) {
+        tracing::warn!("{}", message);
+        state
+            .lock()
+            .await
+            .supervisor
+            .notify(ClusterEventKind::Error {
+                message: message.to_string(),
+            });
+    }
+}
+
+/// Play timelines of faults, so that tests don't have to sequence them with sleeps.
+mod scenario_api {
+    use super::*;
+    use axum::{body::Bytes, headers::ContentType, TypedHeader};
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Serialize, Deserialize, Debug)]
+    pub struct ScenarioRun {
+        pub name: String,
+        pub steps: usize,
+    }
+
+    /// Apply the steps of the scenario at their time. The state is only locked while
+    /// applying a step, so the API stays usable while the scenario runs.
+    pub async fn run(state: SharedState, scenario: Scenario) -> partition_sim::Result<()> {
+        let start = tokio::time::Instant::now();
+        for step in scenario.steps {
+            tokio::time::sleep_until(start + step.at).await;
+            tracing::info!(
+                "Scenario {:?} at {:?}: {:?}",
+                scenario.name,
+                step.at,
+                step.action
             );
-            Ok(String::from_utf8_lossy(&output.stdout).to_string())
+            state
+                .lock()
+                .await
+                .supervisor
+                .apply_step(&step.action)
+                .await?;
+        }
+        tracing::info!("Scenario {:?} is over.", scenario.name);
+        Ok(())
+    }
+
+    /// Start running the scenario in the body, which is TOML if the content type says so,
+    /// and YAML (or JSON) otherwise. Responds right away, while the scenario runs.
+    pub async fn start(
+        State(state): State<SharedState>,
+        content_type: Option<TypedHeader<ContentType>>,
+        body: Bytes,
+    ) -> partition_sim::Result<(StatusCode, Json<ScenarioRun>)> {
+        let text = String::from_utf8_lossy(&body);
+        let is_toml = content_type
+            .map(|TypedHeader(content_type)| content_type.to_string().contains("toml"))
+            .unwrap_or(false);
+        let scenario = if is_toml {
+            Scenario::from_toml(&text)?
         } else {
-            Err(output.into())
+            Scenario::from_yaml(&text)?
+        };
+        let response = ScenarioRun {
+            name: scenario.name.clone(),
+            steps: scenario.steps.len(),
+        };
+        tokio::spawn(async move {
+            let name = scenario.name.clone();
+            if let Err(err) = run(state.clone(), scenario).await {
+                tracing::warn!("Scenario {:?} failed: {}", name, err);
+                state
+                    .lock()
+                    .await
+                    .supervisor
+                    .notify(ClusterEventKind::Error {
+                        message: format!("scenario {:?} failed: {}", name, err),
+                    });
+            }
+        });
+        Ok((StatusCode::ACCEPTED, Json(response)))
+    }
+}
+
+/// Keep applying random faults, reproducibly from a seed.
+mod nemesis_api {
+    use super::*;
+    use axum::extract::Query;
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Serialize, Deserialize, Debug)]
+    pub struct NemesisRun {
+        /// Start a nemesis with this seed (and the same config) to replay the run.
+        pub seed: u64,
+    }
+
+    #[derive(Serialize, Deserialize, Debug)]
+    pub struct PlanParams {
+        pub steps: usize,
+    }
+
+    /// Apply the faults the nemesis picks, forever. A fault that can't be applied is
+    /// logged and skipped, so that one flaky peer doesn't end an overnight run.
+    pub async fn run(state: SharedState, mut nemesis: Nemesis, peer_count: usize) {
+        loop {
+            let (wait, action) = match nemesis.next_step(peer_count) {
+                Ok(step) => step,
+                Err(err) => {
+                    tracing::error!("Nemesis (seed {}) stopped: {}", nemesis.seed(), err);
+                    return;
+                }
+            };
+            tokio::time::sleep(wait).await;
+            let mut guard = state.lock().await;
+            if let Err(err) = guard.supervisor.apply_step(&action).await {
+                tracing::warn!(
+                    "Nemesis (seed {}) couldn't apply {:?}: {}",
+                    nemesis.seed(),
+                    action,
+                    err
+                );
+                guard.supervisor.notify(ClusterEventKind::Error {
+                    message: format!(
+                        "nemesis (seed {}) couldn't apply {:?}: {}",
+                        nemesis.seed(),
+                        action,
+                        err
+                    ),
+                });
+            }
         }
     }
 
-    /// Restore all peers to a clean state.
-    /// This will delete all iptables rules
-    /// and restore the full network to a healthy state.
-    pub async fn restore(State(state): State<SharedState>) -> partition_sim::Result<()> {
+    /// The number of peers the nemesis can pick from, of which it needs at least two.
+    pub fn peer_count(supervisor: &Supervisor) -> partition_sim::Result<usize> {
+        let peer_count = supervisor.get_peer_ids().len();
+        if peer_count < 2 {
+            return Err(partition_sim::Error::InvalidParameter(
+                "the nemesis needs at least two peers".into(),
+            ));
+        }
+        Ok(peer_count)
+    }
+
+    /// Start a nemesis with the given config, replacing the running one if any.
+    /// Responds right away with the seed, while the nemesis runs.
+    pub async fn start(
+        State(state): State<SharedState>,
+        Json(config): Json<NemesisConfig>,
+    ) -> partition_sim::Result<(StatusCode, Json<NemesisRun>)> {
+        let nemesis = Nemesis::new(config)?;
+        let response = NemesisRun {
+            seed: nemesis.seed(),
+        };
         let mut guard = state.lock().await;
-        let peer_ids = guard.supervisor.get_peer_ids().to_vec();
-        for peer_id in peer_ids {
-            guard
-                .supervisor
-                .execute(peer_id, partition_sim::commands::IpTablesCommands::Restore)
-                .await?;
+        let peer_count = peer_count(&guard.supervisor)?;
+        if let Some(previous) = guard.nemesis.take() {
+            previous.abort();
+        }
+        tracing::info!("Starting the nemesis with seed {}.", nemesis.seed());
+        guard.nemesis = Some(tokio::spawn(run(state.clone(), nemesis, peer_count)));
+        Ok((StatusCode::ACCEPTED, Json(response)))
+    }
+
+    /// Stop the running nemesis. The faults it applied stay until healed or restored.
+    pub async fn stop(State(state): State<SharedState>) -> partition_sim::Result<()> {
+        if let Some(nemesis) = state.lock().await.nemesis.take() {
+            nemesis.abort();
+            tracing::info!("Stopped the nemesis.");
+        }
+        Ok(())
+    }
+
+    /// The first faults a nemesis with the given config would apply, as a scenario
+    /// that can be edited and run on its own.
+    pub async fn plan(
+        Query(params): Query<PlanParams>,
+        State(state): State<SharedState>,
+        Json(config): Json<NemesisConfig>,
+    ) -> partition_sim::Result<Json<Scenario>> {
+        let peer_count = peer_count(&state.lock().await.supervisor)?;
+        Ok(Json(Nemesis::new(config)?.plan(peer_count, params.steps)?))
+    }
+}
+
+/// Follow the faults as they get applied and healed, instead of polling every peer.
+mod events_api {
+    use super::*;
+    use axum::{
+        http::Request,
+        middleware::Next,
+        response::{
+            sse::{Event, KeepAlive, Sse},
+            Response,
+        },
+    };
+    use partition_sim::errors::ErrorMessage;
+    use tokio_stream::{
+        wrappers::{errors::BroadcastStreamRecvError, BroadcastStream},
+        Stream, StreamExt,
+    };
+
+    /// Stream every partition, heal, restore, cluster load and error as a server-sent
+    /// event, with the details as JSON.
+    pub async fn follow(
+        State(state): State<SharedState>,
+    ) -> Sse<impl Stream<Item = Result<Event, serde_json::Error>>> {
+        let cluster_events = state.lock().await.cluster_events.subscribe();
+        let stream =
+            BroadcastStream::new(cluster_events).map(|cluster_event| match cluster_event {
+                Ok(cluster_event) => Event::default().json_data(cluster_event),
+                // A follower too slow to keep up misses some events; tell it how many.
+                Err(BroadcastStreamRecvError::Lagged(missed)) => {
+                    Ok(Event::default().event("lagged").data(missed.to_string()))
+                }
+            });
+        Sse::new(stream).keep_alive(KeepAlive::default())
+    }
+
+    /// Announce the requests that failed, along with why.
+    pub async fn report_errors<B>(
+        State(cluster_events): State<broadcast::Sender<ClusterEvent>>,
+        request: Request<B>,
+        next: Next<B>,
+    ) -> Response {
+        let endpoint = format!("{} {}", request.method(), request.uri().path());
+        let response = next.run(request).await;
+        if let Some(ErrorMessage(message)) = response.extensions().get::<ErrorMessage>() {
+            let kind = ClusterEventKind::Error {
+                message: format!("{}: {}", endpoint, message),
+            };
+            let _ = cluster_events.send(kind.into());
         }
-        tracing::debug!("Restored all the iptables rules. Network should be healthy now.");
+        response
+    }
+}
+
+/// Capture the firewall rules across the cluster once and flip back to them at will.
+mod snapshot_api {
+    use super::*;
+    use axum::extract::Path;
+    use partition_sim::Snapshot;
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Serialize, Deserialize, Debug)]
+    pub struct SnapshotRequest {
+        pub name: String,
+    }
+
+    /// Capture the current firewall rules of every peer into a named snapshot.
+    pub async fn take_snapshot(
+        State(state): State<SharedState>,
+        Json(request): Json<SnapshotRequest>,
+    ) -> partition_sim::Result<Json<Snapshot>> {
+        let mut guard = state.lock().await;
+        let snapshot = guard.supervisor.take_snapshot(&request.name).await?.clone();
+        tracing::debug!(
+            "Took snapshot {} of {} peers.",
+            snapshot.name,
+            snapshot.rules.len()
+        );
+        Ok(Json(snapshot))
+    }
+
+    /// Get a previously taken snapshot.
+    pub async fn get_snapshot(
+        Path(name): Path<String>,
+        State(state): State<SharedState>,
+    ) -> partition_sim::Result<Json<Snapshot>> {
+        let guard = state.lock().await;
+        Ok(Json(guard.supervisor.get_snapshot(&name)?.clone()))
+    }
+
+    /// Replace the firewall rules of every peer with the ones in a previously taken snapshot.
+    pub async fn apply_snapshot(
+        Path(name): Path<String>,
+        State(state): State<SharedState>,
+    ) -> partition_sim::Result<()> {
+        let mut guard = state.lock().await;
+        guard.supervisor.apply_snapshot(&name).await?;
+        tracing::debug!("Applied snapshot {}.", name);
         Ok(())
     }
 }
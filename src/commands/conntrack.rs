@@ -0,0 +1,79 @@
+use std::net::IpAddr;
+
+use serde::{Deserialize, Serialize};
+
+use super::Commands;
+
+/// Connection tracking (`conntrack`) commands make the target node forget about
+/// connections it has already seen, so that a new firewall rule also applies
+/// to the packets of established connections.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ConntrackCommands {
+    /// Delete all the tracked connections in the target node that were opened from a given source IP.
+    FlushFrom { source_ip: IpAddr },
+    /// Delete all the tracked connections in the target node that were opened to a given destination IP.
+    FlushTo { destination_ip: IpAddr },
+}
+
+impl From<ConntrackCommands> for Commands {
+    fn from(command: ConntrackCommands) -> Self {
+        Self::Conntrack(command)
+    }
+}
+
+/// The `conntrack` arguments that select the connections from (`-s`) or to (`-d`) the given IP.
+fn selector(address_flag: &str, ip_addr: &IpAddr) -> String {
+    match ip_addr {
+        IpAddr::V4(addr) => format!("-f ipv4 {} {}", address_flag, addr),
+        IpAddr::V6(addr) => format!("-f ipv6 {} {}", address_flag, addr),
+    }
+}
+
+/// Delete the selected connections. `conntrack` exits with an error when there was
+/// nothing to delete, which is fine by us, so we only fail if it isn't installed.
+fn delete(selector: &str) -> String {
+    format!("test -x /usr/sbin/conntrack && {{ /usr/sbin/conntrack -D {selector} 2>&1; true; }}")
+}
+
+impl ConntrackCommands {
+    /// The shell script that gets run (as root) on the target node.
+    pub fn script(&self) -> String {
+        match self {
+            Self::FlushFrom { source_ip } => delete(&selector("-s", source_ip)),
+            Self::FlushTo { destination_ip } => delete(&selector("-d", destination_ip)),
+        }
+    }
+}
+
+impl super::Command for ConntrackCommands {
+    fn build<'session>(&self, session: &'session openssh::Session) -> openssh::Command<'session> {
+        super::sudo_script(session, self.script())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_flush_from_script() {
+        assert_eq!(
+            ConntrackCommands::FlushFrom {
+                source_ip: "192.168.192.4".parse().unwrap(),
+            }
+            .script(),
+            "test -x /usr/sbin/conntrack && { /usr/sbin/conntrack -D -f ipv4 -s 192.168.192.4 2>&1; true; }"
+        );
+    }
+
+    #[test]
+    fn test_flush_to_ipv6_script() {
+        let script = ConntrackCommands::FlushTo {
+            destination_ip: "fd00::4".parse().unwrap(),
+        }
+        .script();
+        // Nodes without `conntrack` fail the command instead of pretending to flush.
+        assert!(script.starts_with("test -x /usr/sbin/conntrack && "));
+        assert!(script.contains("/usr/sbin/conntrack -D -f ipv6 -d fd00::4 2>&1"));
+    }
+}
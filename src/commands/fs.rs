@@ -1,9 +1,11 @@
+use serde::{Deserialize, Serialize};
+
 use super::{Command, Commands};
 
 /// Some file-system related commands for debugging/testing
 /// purposes so that we don't have to run `iptables` commands
 /// which are potentially destructive.
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum FsCommands {
     /// List the contents of the current directory.
     Ls,
@@ -1,21 +1,224 @@
+use std::fmt::Display;
 use std::net::IpAddr;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
 
 use super::Commands;
 
+/// The chain that holds all the rules we add on a node, so that we never
+/// touch any of the rules that Docker or the application set up.
+pub const CHAIN: &str = "PARTITION_SIM";
+
 /// All Iptables commands require root privileges
 /// so we'll run them with `sudo` assuming that the user
 /// has sudo access. We'll fail otherwise.
-#[derive(Debug, Clone, PartialEq, Eq)]
+///
+/// Every rule lives in our own chain ([`CHAIN`]), which gets created on
+/// the target node (and jumped to from `INPUT` and `OUTPUT`) on demand.
+/// Rules for IPv6 addresses go through `ip6tables` instead of `iptables`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum IpTablesCommands {
-    /// Flush all rules across all the peers so that all nodes
-    /// can communicate with each other.
+    /// Flush all our rules in the target node so that it
+    /// can communicate with all the other nodes.
     Restore,
     /// Remove all inbound rules in the target node for a given source IP.
-    RestoreFrom { source_ip: IpAddr },
-    /// Add a rule to drop all inbound traffic into the target node originating from a given source IP.
-    DropFrom { source_ip: IpAddr },
-    /// List all inbound rules in the target node.
+    /// Every copy of a matching rule gets removed.
+    ///
+    /// The `filter`, `probability` and `action` must match the ones the rule was added with.
+    RestoreFrom {
+        source_ip: IpAddr,
+        filter: Filter,
+        probability: Option<f64>,
+        action: Action,
+    },
+    /// Add a rule to drop all inbound traffic into the target node originating from a given source IP,
+    /// unless the same rule is already there.
+    ///
+    /// The `filter` narrows the rule down to a protocol and destination port(s). If a
+    /// `probability` (between 0 and 1) is given, each packet is only dropped with that
+    /// probability instead. The `action` picks whether the packets vanish or get rejected.
+    DropFrom {
+        source_ip: IpAddr,
+        filter: Filter,
+        probability: Option<f64>,
+        action: Action,
+    },
+    /// Remove all outbound rules in the target node for a given destination IP.
+    /// Every copy of a matching rule gets removed.
+    ///
+    /// The `filter`, `probability` and `action` must match the ones the rule was added with.
+    RestoreTo {
+        destination_ip: IpAddr,
+        filter: Filter,
+        probability: Option<f64>,
+        action: Action,
+    },
+    /// Add a rule to drop all outbound traffic from the target node destined to a given destination IP,
+    /// unless the same rule is already there.
+    ///
+    /// The `filter` narrows the rule down to a protocol and destination port(s). If a
+    /// `probability` (between 0 and 1) is given, each packet is only dropped with that
+    /// probability instead. The `action` picks whether the packets vanish or get rejected.
+    DropTo {
+        destination_ip: IpAddr,
+        filter: Filter,
+        probability: Option<f64>,
+        action: Action,
+    },
+    /// List all our (inbound and outbound) rules in the target node, in the `iptables -S` format.
     Get,
+    /// Dump all our rules in the target node, in the `iptables-save` format.
+    Save,
+    /// Replace all our rules in the target node with the ones previously dumped by `Save`.
+    Load { rules: String },
+    /// Run several `DropFrom`, `DropTo`, `RestoreFrom` and `RestoreTo` commands in one go,
+    /// stopping at the first one that fails, and print the total number of rules they changed.
+    Batch(Vec<IpTablesCommands>),
+}
+
+/// Which way the traffic between the target node and a peer gets cut.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    /// Only the packets the peer sends to the target node (matched on their source).
+    #[default]
+    Inbound,
+    /// Only the packets the target node sends to the peer (matched on their destination).
+    Outbound,
+    /// The packets going either way.
+    Both,
+}
+
+impl Direction {
+    pub fn inbound(&self) -> bool {
+        matches!(self, Self::Inbound | Self::Both)
+    }
+
+    pub fn outbound(&self) -> bool {
+        matches!(self, Self::Outbound | Self::Both)
+    }
+}
+
+/// What happens to the packets a rule matches.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    /// The packets silently vanish, so the sender hangs until it times out.
+    #[default]
+    Drop,
+    /// The packets get answered with a TCP RST (for TCP rules) or an ICMP
+    /// port-unreachable (otherwise), so the sender fails fast.
+    Reject,
+}
+
+/// The protocols a rule can be narrowed down to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    Tcp,
+    Udp,
+    Icmp,
+}
+
+impl Display for Protocol {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Tcp => write!(f, "tcp"),
+            Self::Udp => write!(f, "udp"),
+            Self::Icmp => write!(f, "icmp"),
+        }
+    }
+}
+
+/// An inclusive range of ports, written as `9000` or `9000:9010`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct PortRange {
+    pub start: u16,
+    pub end: u16,
+}
+
+impl FromStr for PortRange {
+    type Err = crate::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || crate::Error::InvalidParameter(format!("invalid port range: {}", s));
+        let (start, end) = match s.split_once(':') {
+            Some((start, end)) => (start, end),
+            None => (s, s),
+        };
+        let start = start.trim().parse::<u16>().map_err(|_| invalid())?;
+        let end = end.trim().parse::<u16>().map_err(|_| invalid())?;
+        if start > end {
+            return Err(invalid());
+        }
+        Ok(Self { start, end })
+    }
+}
+
+impl TryFrom<String> for PortRange {
+    type Error = crate::Error;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl Display for PortRange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.start == self.end {
+            write!(f, "{}", self.start)
+        } else {
+            write!(f, "{}:{}", self.start, self.end)
+        }
+    }
+}
+
+impl From<PortRange> for String {
+    fn from(value: PortRange) -> Self {
+        value.to_string()
+    }
+}
+
+/// Narrows a rule down to the packets of a given protocol and/or destination port(s).
+/// An empty filter matches all the traffic.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct Filter {
+    pub protocol: Option<Protocol>,
+    /// The destination port(s). Requires the `tcp` or `udp` protocol.
+    pub ports: Option<PortRange>,
+}
+
+impl Filter {
+    pub fn validate(&self) -> crate::Result<()> {
+        match (self.protocol, self.ports) {
+            (None | Some(Protocol::Icmp), Some(_)) => Err(crate::Error::InvalidParameter(
+                "ports require the tcp or udp protocol".into(),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    fn args(&self, ip_addr: &IpAddr) -> Vec<String> {
+        let mut args = vec![];
+        match self.protocol {
+            Some(Protocol::Icmp) if ip_addr.is_ipv6() => {
+                args.push("-p".into());
+                args.push("ipv6-icmp".into());
+            }
+            Some(protocol) => {
+                args.push("-p".into());
+                args.push(protocol.to_string());
+            }
+            None => {}
+        }
+        if let Some(ports) = self.ports {
+            args.push("--dport".into());
+            args.push(ports.to_string());
+        }
+        args
+    }
 }
 
 impl From<IpTablesCommands> for Commands {
@@ -24,45 +227,413 @@ impl From<IpTablesCommands> for Commands {
     }
 }
 
-impl super::Command for IpTablesCommands {
-    fn build<'session>(&self, session: &'session openssh::Session) -> openssh::Command<'session> {
-        match self {
-            Self::Restore => {
-                let mut command = session.raw_command("sudo");
-                command.arg("/usr/sbin/iptables");
-                command.arg("-F");
-                command
+/// The `iptables` binary that manages the rules for the given address family.
+fn binary(ip_addr: &IpAddr) -> &'static str {
+    match ip_addr {
+        IpAddr::V4(_) => IPTABLES,
+        IpAddr::V6(_) => IP6TABLES,
+    }
+}
+
+const IPTABLES: &str = "/usr/sbin/iptables";
+const IP6TABLES: &str = "/usr/sbin/ip6tables";
+
+/// The line that separates the IPv4 rules from the IPv6 ones in the output of `Save`.
+const IP6TABLES_MARKER: &str = "# ip6tables";
+
+/// Quote the text so that the shell passes it along as is.
+pub(super) fn quote(text: &str) -> String {
+    format!("'{}'", text.replace('\'', "'\\''"))
+}
+
+/// Make sure our chain exists and that both the `INPUT` and the `OUTPUT` chains
+/// jump to it before any of the node's own rules get a say.
+fn ensure_chain(binary: &str) -> String {
+    [
+        format!("{binary} -N {CHAIN} 2>/dev/null"),
+        format!("({binary} -C INPUT -j {CHAIN} 2>/dev/null || {binary} -I INPUT -j {CHAIN})"),
+        format!("({binary} -C OUTPUT -j {CHAIN} 2>/dev/null || {binary} -I OUTPUT -j {CHAIN})"),
+    ]
+    .join("; ")
+}
+
+/// Run the `iptables` command (given without the binary) on our chain for both address
+/// families. Only the IPv4 one has to succeed, since IPv6 might be disabled on the node.
+fn for_both_families(command: &str) -> String {
+    format!(
+        "{}; {IPTABLES} {command} && {{ ({}; {IP6TABLES} {command}) 2>/dev/null; true; }}",
+        ensure_chain(IPTABLES),
+        ensure_chain(IP6TABLES),
+    )
+}
+
+/// The rule specification (everything after the chain) shared by the commands
+/// that add and delete rules for a given source (`-s`) or destination (`-d`) IP.
+pub(super) fn drop_rule(
+    address_flag: &str,
+    ip_addr: &IpAddr,
+    filter: &Filter,
+    probability: &Option<f64>,
+    action: &Action,
+) -> Vec<String> {
+    let mut rule = vec![address_flag.to_string(), ip_addr.to_string()];
+    rule.extend(filter.args(ip_addr));
+    if let Some(probability) = probability {
+        rule.push("-m".into());
+        rule.push("statistic".into());
+        rule.push("--mode".into());
+        rule.push("random".into());
+        rule.push("--probability".into());
+        rule.push(probability.to_string());
+    }
+    rule.push("-j".into());
+    match action {
+        Action::Drop => rule.push("DROP".into()),
+        Action::Reject => {
+            rule.push("REJECT".into());
+            rule.push("--reject-with".into());
+            rule.push(
+                match (filter.protocol, ip_addr) {
+                    (Some(Protocol::Tcp), _) => "tcp-reset",
+                    (_, IpAddr::V4(_)) => "icmp-port-unreachable",
+                    (_, IpAddr::V6(_)) => "icmp6-port-unreachable",
+                }
+                .into(),
+            );
+        }
+    }
+    rule
+}
+
+/// Append the rule to our chain unless an identical rule is already there.
+/// Prints the number of rules added (0 or 1).
+fn append_once(binary: &str, rule: &[String]) -> String {
+    let rule = rule.join(" ");
+    format!(
+        "{}; if {binary} -C {CHAIN} {rule} 2>/dev/null; then echo 0; \
+         else {binary} -A {CHAIN} {rule} && echo 1; fi",
+        ensure_chain(binary)
+    )
+}
+
+/// Delete every copy of the rule from our chain.
+/// Prints the number of rules deleted.
+fn delete_all(binary: &str, rule: &[String]) -> String {
+    let rule = rule.join(" ");
+    format!(
+        "{}; n=0; while {binary} -D {CHAIN} {rule} 2>/dev/null; do n=$((n+1)); done; echo $n",
+        ensure_chain(binary)
+    )
+}
+
+/// Run the scripts one after the other, stopping at the first one that fails,
+/// and print the sum of the numbers of rules they changed.
+pub(super) fn sum_changes(scripts: impl Iterator<Item = String>) -> String {
+    let mut script = String::from("total=0");
+    for command in scripts {
+        script.push_str(&format!(
+            " && changed=$({command}) && total=$((total + changed))"
+        ));
+    }
+    script.push_str(" && echo $total");
+    script
+}
+
+impl IpTablesCommands {
+    /// The number of rules a successful `DropFrom`, `DropTo`, `RestoreFrom` or `RestoreTo`
+    /// added or deleted, as printed on its stdout. Adding a rule that is already there
+    /// or deleting one that isn't changes nothing, so these commands are idempotent.
+    pub fn rules_changed(stdout: &[u8]) -> crate::Result<usize> {
+        let stdout = String::from_utf8_lossy(stdout);
+        stdout.trim().parse::<usize>().map_err(|_| {
+            crate::Error::Other(format!(
+                "Expected the number of changed rules but got: {}",
+                stdout
+            ))
+        })
+    }
+
+    /// The specification (in the `iptables -S` format, without the chain) of the rule
+    /// that a `DropFrom`, `DropTo`, `RestoreFrom` or `RestoreTo` adds or deletes.
+    pub fn rule_spec(&self) -> Option<String> {
+        let rule = match self {
+            Self::RestoreFrom {
+                source_ip,
+                filter,
+                probability,
+                action,
+            }
+            | Self::DropFrom {
+                source_ip,
+                filter,
+                probability,
+                action,
+            } => drop_rule("-s", source_ip, filter, probability, action),
+            Self::RestoreTo {
+                destination_ip,
+                filter,
+                probability,
+                action,
             }
-            Self::RestoreFrom { source_ip } => {
-                let mut command = session.raw_command("sudo");
-                command.arg("/usr/sbin/iptables");
-                command.arg("-D");
-                command.arg("INPUT");
-                command.arg("-s");
-                command.arg(source_ip.to_string());
-                command.arg("-j");
-                command.arg("DROP");
-                command
+            | Self::DropTo {
+                destination_ip,
+                filter,
+                probability,
+                action,
+            } => drop_rule("-d", destination_ip, filter, probability, action),
+            Self::Restore | Self::Get | Self::Save | Self::Load { .. } | Self::Batch(_) => {
+                return None
             }
-            Self::DropFrom { source_ip } => {
-                let mut command = session.raw_command("sudo");
-                command.arg("/usr/sbin/iptables");
-                command.arg("-A");
-                command.arg("INPUT");
-                command.arg("-s");
-                command.arg(source_ip.to_string());
-                command.arg("-j");
-                command.arg("DROP");
-                command
+        };
+        Some(rule.join(" "))
+    }
+
+    /// The shell script that gets run (as root) on the target node.
+    pub fn script(&self) -> String {
+        match self {
+            Self::Restore => for_both_families(&format!("-F {CHAIN}")),
+            Self::RestoreFrom {
+                source_ip,
+                filter,
+                probability,
+                action,
+            } => delete_all(
+                binary(source_ip),
+                &drop_rule("-s", source_ip, filter, probability, action),
+            ),
+            Self::DropFrom {
+                source_ip,
+                filter,
+                probability,
+                action,
+            } => append_once(
+                binary(source_ip),
+                &drop_rule("-s", source_ip, filter, probability, action),
+            ),
+            Self::RestoreTo {
+                destination_ip,
+                filter,
+                probability,
+                action,
+            } => delete_all(
+                binary(destination_ip),
+                &drop_rule("-d", destination_ip, filter, probability, action),
+            ),
+            Self::DropTo {
+                destination_ip,
+                filter,
+                probability,
+                action,
+            } => append_once(
+                binary(destination_ip),
+                &drop_rule("-d", destination_ip, filter, probability, action),
+            ),
+            Self::Get => for_both_families(&format!("-S {CHAIN}")),
+            Self::Save => {
+                let save = |binary: &str| {
+                    format!(
+                        "{binary}-save -t filter | grep -E '^(\\*filter|:{CHAIN} |-A {CHAIN} |COMMIT)'"
+                    )
+                };
+                format!(
+                    "{}; {} && {{ ({} && echo '{IP6TABLES_MARKER}' && {}) 2>/dev/null; true; }}",
+                    ensure_chain(IPTABLES),
+                    save(IPTABLES),
+                    ensure_chain(IP6TABLES),
+                    save(IP6TABLES),
+                )
             }
-            Self::Get => {
-                let mut command = session.raw_command("sudo");
-                command.arg("/usr/sbin/iptables");
-                command.arg("-L");
-                command.arg("INPUT");
-                command.arg("-n");
-                command
+            Self::Load { rules } => {
+                let (rules, rules_v6) = rules.split_once(IP6TABLES_MARKER).unwrap_or((rules, ""));
+                let load = |binary: &str, rules: &str| {
+                    format!(
+                        "{}; printf '%s\\n' {} | {binary}-restore --noflush",
+                        ensure_chain(binary),
+                        quote(rules.trim())
+                    )
+                };
+                let mut script = load(IPTABLES, rules);
+                if rules_v6.contains("*filter") {
+                    script.push_str(&format!(" && {{ {}; }}", load(IP6TABLES, rules_v6)));
+                }
+                script
             }
+            Self::Batch(commands) => sum_changes(commands.iter().map(|command| command.script())),
+        }
+    }
+}
+
+impl super::Command for IpTablesCommands {
+    fn build<'session>(&self, session: &'session openssh::Session) -> openssh::Command<'session> {
+        super::sudo_script(session, self.script())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_batch_script() {
+        let drop = IpTablesCommands::DropFrom {
+            source_ip: "192.168.192.4".parse().unwrap(),
+            filter: Filter::default(),
+            probability: None,
+            action: Action::Drop,
+        };
+        let script = IpTablesCommands::Batch(vec![drop.clone(), drop.clone()]).script();
+        assert_eq!(
+            script,
+            format!(
+                "total=0 && changed=$({0}) && total=$((total + changed)) \
+                 && changed=$({0}) && total=$((total + changed)) && echo $total",
+                drop.script()
+            )
+        );
+    }
+
+    #[test]
+    fn test_port_range_from_str() {
+        assert_eq!(
+            "9000".parse::<PortRange>().unwrap(),
+            PortRange {
+                start: 9000,
+                end: 9000
+            }
+        );
+        assert_eq!(
+            "9000:9010".parse::<PortRange>().unwrap().to_string(),
+            "9000:9010"
+        );
+        assert!("9010:9000".parse::<PortRange>().is_err());
+        assert!("http".parse::<PortRange>().is_err());
+    }
+
+    #[test]
+    fn test_drop_from_script() {
+        let script = IpTablesCommands::DropFrom {
+            source_ip: "192.168.192.4".parse().unwrap(),
+            filter: Filter::default(),
+            probability: None,
+            action: Action::Drop,
+        }
+        .script();
+        assert!(script.starts_with("/usr/sbin/iptables -N PARTITION_SIM"));
+        assert!(script.contains("/usr/sbin/iptables -I INPUT -j PARTITION_SIM"));
+        assert!(script.ends_with(
+            "if /usr/sbin/iptables -C PARTITION_SIM -s 192.168.192.4 -j DROP 2>/dev/null; then echo 0; \
+             else /usr/sbin/iptables -A PARTITION_SIM -s 192.168.192.4 -j DROP && echo 1; fi"
+        ));
+    }
+
+    #[test]
+    fn test_restore_from_script() {
+        let script = IpTablesCommands::RestoreFrom {
+            source_ip: "192.168.192.4".parse().unwrap(),
+            filter: Filter::default(),
+            probability: None,
+            action: Action::Drop,
+        }
+        .script();
+        assert!(script.ends_with(
+            "while /usr/sbin/iptables -D PARTITION_SIM -s 192.168.192.4 -j DROP 2>/dev/null; do n=$((n+1)); done; echo $n"
+        ));
+        assert_eq!(IpTablesCommands::rules_changed(b"2\n").unwrap(), 2);
+    }
+
+    #[test]
+    fn test_drop_to_ipv6_script() {
+        let script = IpTablesCommands::DropTo {
+            destination_ip: "fd00::4".parse().unwrap(),
+            filter: Filter {
+                protocol: Some(Protocol::Icmp),
+                ports: None,
+            },
+            probability: None,
+            action: Action::Drop,
+        }
+        .script();
+        assert!(!script.contains("/usr/sbin/iptables "));
+        assert!(script.ends_with(
+            "else /usr/sbin/ip6tables -A PARTITION_SIM -d fd00::4 -p ipv6-icmp -j DROP && echo 1; fi"
+        ));
+    }
+
+    #[test]
+    fn test_load_script() {
+        let rules = "*filter\n:PARTITION_SIM - [0:0]\n-A PARTITION_SIM -s 192.168.192.4/32 -j DROP\nCOMMIT\n";
+        let script = IpTablesCommands::Load {
+            rules: rules.into(),
+        }
+        .script();
+        assert!(script.ends_with(
+            "printf '%s\\n' '*filter\n:PARTITION_SIM - [0:0]\n-A PARTITION_SIM -s 192.168.192.4/32 -j DROP\nCOMMIT' \
+             | /usr/sbin/iptables-restore --noflush"
+        ));
+        assert!(!script.contains("ip6tables-restore"));
+
+        let script = IpTablesCommands::Load {
+            rules: format!(
+                "{}# ip6tables\n*filter\n:PARTITION_SIM - [0:0]\nCOMMIT\n",
+                rules
+            ),
+        }
+        .script();
+        assert!(script.ends_with(
+            "printf '%s\\n' '*filter\n:PARTITION_SIM - [0:0]\nCOMMIT' | /usr/sbin/ip6tables-restore --noflush; }"
+        ));
+    }
+
+    #[test]
+    fn test_quote() {
+        assert_eq!(quote("it's"), "'it'\\''s'");
+    }
+
+    #[test]
+    fn test_drop_rule() {
+        let filter = Filter {
+            protocol: Some(Protocol::Tcp),
+            ports: Some("9000".parse().unwrap()),
+        };
+        filter.validate().unwrap();
+        assert_eq!(
+            drop_rule(
+                "-s",
+                &"192.168.192.4".parse().unwrap(),
+                &filter,
+                &Some(0.2),
+                &Action::Drop
+            )
+            .join(" "),
+            "-s 192.168.192.4 -p tcp --dport 9000 -m statistic --mode random --probability 0.2 -j DROP"
+        );
+        assert_eq!(
+            drop_rule(
+                "-s",
+                &"192.168.192.4".parse().unwrap(),
+                &filter,
+                &None,
+                &Action::Reject
+            )
+            .join(" "),
+            "-s 192.168.192.4 -p tcp --dport 9000 -j REJECT --reject-with tcp-reset"
+        );
+        assert_eq!(
+            drop_rule(
+                "-d",
+                &"fd00::4".parse().unwrap(),
+                &Filter::default(),
+                &None,
+                &Action::Reject
+            )
+            .join(" "),
+            "-d fd00::4 -j REJECT --reject-with icmp6-port-unreachable"
+        );
+        assert!(Filter {
+            protocol: None,
+            ports: Some("9000".parse().unwrap()),
         }
+        .validate()
+        .is_err());
     }
 }
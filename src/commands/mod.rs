@@ -1,10 +1,18 @@
+mod conntrack;
 mod fs;
 mod ip;
+mod nft;
 mod ssh;
+mod tc;
 
+use serde::{Deserialize, Serialize};
+
+pub use conntrack::ConntrackCommands;
 pub use fs::FsCommands;
-pub use ip::IpTablesCommands;
+pub use ip::{Action, Direction, Filter, IpTablesCommands, PortRange, Protocol};
+pub use nft::{spec_from_comment, NfTablesCommands};
 pub use ssh::SshCommands;
+pub use tc::{Netem, TcCommands};
 
 /// A command should be able to build itself into an `openssh::Command`, given
 /// an `openssh::Session`.
@@ -13,11 +21,26 @@ pub trait Command {
     fn build<'session>(&self, session: &'session openssh::Session) -> openssh::Command<'session>;
 }
 
+/// Run the shell script as root on the node the session is connected to.
+fn sudo_script(session: &openssh::Session, script: String) -> openssh::Command<'_> {
+    let mut command = session.raw_command("sudo");
+    command.arg("sh");
+    command.arg("-c");
+    command.arg(script);
+    command
+}
+
 /// A wrapper around all commands.
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum Commands {
     /// All `iptables` related commands.
     IpTables(IpTablesCommands),
+    /// All `nft` related commands.
+    NfTables(NfTablesCommands),
+    /// All `tc` (traffic control) related commands.
+    Tc(TcCommands),
+    /// All `conntrack` related commands.
+    Conntrack(ConntrackCommands),
     /// All file-system related commands.
     Fs(FsCommands),
 }
@@ -26,6 +49,9 @@ impl Command for Commands {
     fn build<'session>(&self, session: &'session openssh::Session) -> openssh::Command<'session> {
         match self {
             Self::IpTables(command) => command.build(session),
+            Self::NfTables(command) => command.build(session),
+            Self::Tc(command) => command.build(session),
+            Self::Conntrack(command) => command.build(session),
             Self::Fs(command) => command.build(session),
         }
     }
@@ -0,0 +1,336 @@
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+use super::{Action, Commands, Filter, IpTablesCommands, PortRange, Protocol};
+
+/// The nftables table that holds all the rules we add on a node.
+pub const TABLE: &str = "partition_sim";
+
+/// The nftables equivalent of an [`IpTablesCommands`], for nodes that ship `nft`
+/// without the legacy `iptables`.
+///
+/// Every rule lives in the `input` or `output` chain of our own `inet` table ([`TABLE`]),
+/// hooked in just before the default filter priority. Each rule carries a key made of
+/// its parameters as its comment (see [`rule_key`]), which is how a rule gets found again
+/// to check for duplicates, to delete it or to list it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NfTablesCommands(pub IpTablesCommands);
+
+impl From<NfTablesCommands> for Commands {
+    fn from(command: NfTablesCommands) -> Self {
+        Self::NfTables(command)
+    }
+}
+
+impl From<IpTablesCommands> for NfTablesCommands {
+    fn from(command: IpTablesCommands) -> Self {
+        Self(command)
+    }
+}
+
+/// Make sure our table and its chains exist. `nft add` is a no-op for the ones that do.
+fn ensure_table() -> String {
+    [
+        format!("/usr/sbin/nft add table inet {TABLE}"),
+        format!(
+            "/usr/sbin/nft 'add chain inet {TABLE} input {{ type filter hook input priority -10; }}'"
+        ),
+        format!(
+            "/usr/sbin/nft 'add chain inet {TABLE} output {{ type filter hook output priority -10; }}'"
+        ),
+    ]
+    .join("; ")
+}
+
+/// The nftables rule (everything after the chain) that drops the traffic
+/// from (`saddr`) or to (`daddr`) the given IP.
+fn drop_rule(
+    address: &str,
+    ip_addr: &IpAddr,
+    filter: &Filter,
+    probability: &Option<f64>,
+    action: &Action,
+) -> String {
+    let mut rule = vec![match ip_addr {
+        IpAddr::V4(addr) => format!("ip {} {}", address, addr),
+        IpAddr::V6(addr) => format!("ip6 {} {}", address, addr),
+    }];
+    match (filter.protocol, filter.ports) {
+        (Some(protocol), Some(ports)) => {
+            rule.push(format!("{} dport {}-{}", protocol, ports.start, ports.end));
+        }
+        (Some(Protocol::Icmp), None) if ip_addr.is_ipv6() => {
+            rule.push("meta l4proto ipv6-icmp".into());
+        }
+        (Some(protocol), None) => {
+            rule.push(format!("meta l4proto {}", protocol));
+        }
+        (None, _) => {}
+    }
+    if let Some(probability) = probability {
+        rule.push(format!(
+            "numgen random mod 1000000 < {}",
+            (probability * 1_000_000.0).round() as u32
+        ));
+    }
+    rule.push(
+        match (action, filter.protocol) {
+            (Action::Drop, _) => "drop",
+            (Action::Reject, Some(Protocol::Tcp)) => "reject with tcp reset",
+            (Action::Reject, _) => "reject",
+        }
+        .into(),
+    );
+    rule.join(" ")
+}
+
+/// The comment that identifies a rule. nftables caps comments at 128 bytes, which the
+/// `iptables` rule specification can go past, so it's only the parameters of the rule
+/// instead, like `-s fd00::4 tcp 9000:9010 0.2 reject`, with `-` for the ones not given.
+fn rule_key(
+    address_flag: &str,
+    ip_addr: &IpAddr,
+    filter: &Filter,
+    probability: &Option<f64>,
+    action: &Action,
+) -> String {
+    let or_dash = |value: Option<String>| value.unwrap_or_else(|| "-".into());
+    format!(
+        "{} {} {} {} {} {}",
+        address_flag,
+        ip_addr,
+        or_dash(filter.protocol.map(|protocol| protocol.to_string())),
+        or_dash(filter.ports.map(|ports| ports.to_string())),
+        or_dash(probability.map(|probability| probability.to_string())),
+        match action {
+            Action::Drop => "drop",
+            Action::Reject => "reject",
+        }
+    )
+}
+
+/// The `iptables` rule specification of the rule with the given comment, if the
+/// comment is a [`rule_key`]. Older rules carry the specification itself instead.
+pub fn spec_from_comment(comment: &str) -> Option<String> {
+    let optional = |value: &str| (value != "-").then(|| value.to_string());
+    let [address_flag, ip_addr, protocol, ports, probability, action] =
+        comment.split(' ').collect::<Vec<_>>().try_into().ok()?;
+    let protocol = match optional(protocol).as_deref() {
+        Some("tcp") => Some(Protocol::Tcp),
+        Some("udp") => Some(Protocol::Udp),
+        Some("icmp") => Some(Protocol::Icmp),
+        Some(_) => return None,
+        None => None,
+    };
+    let ports = match optional(ports) {
+        Some(ports) => Some(PortRange::from_str(&ports).ok()?),
+        None => None,
+    };
+    let probability = match optional(probability) {
+        Some(probability) => Some(probability.parse().ok()?),
+        None => None,
+    };
+    let action = match action {
+        "drop" => Action::Drop,
+        "reject" => Action::Reject,
+        _ => return None,
+    };
+    if !matches!(address_flag, "-s" | "-d") {
+        return None;
+    }
+    Some(
+        super::ip::drop_rule(
+            address_flag,
+            &ip_addr.parse().ok()?,
+            &Filter { protocol, ports },
+            &probability,
+            &action,
+        )
+        .join(" "),
+    )
+}
+
+/// Append the rule to the chain unless a rule with the same comment is already there.
+/// Prints the number of rules added (0 or 1).
+fn append_once(chain: &str, rule: &str, comment: &str) -> String {
+    format!(
+        "if /usr/sbin/nft list chain inet {TABLE} {chain} | grep -qF 'comment \"{comment}\"'; then echo 0; \
+         else /usr/sbin/nft 'add rule inet {TABLE} {chain} {rule} comment \"{comment}\"' && echo 1; fi"
+    )
+}
+
+/// Delete every rule with the given comment from the chain.
+/// Prints the number of rules deleted.
+fn delete_all(chain: &str, comment: &str) -> String {
+    format!(
+        "n=0; for handle in $(/usr/sbin/nft -a list chain inet {TABLE} {chain} \
+         | grep -F 'comment \"{comment}\"' | sed 's/.*# handle //'); do \
+         /usr/sbin/nft delete rule inet {TABLE} {chain} handle $handle && n=$((n+1)); done; echo $n"
+    )
+}
+
+impl NfTablesCommands {
+    /// The shell script that gets run (as root) on the target node.
+    pub fn script(&self) -> String {
+        match &self.0 {
+            IpTablesCommands::Restore => {
+                format!("{}; /usr/sbin/nft flush table inet {TABLE}", ensure_table())
+            }
+            IpTablesCommands::RestoreFrom {
+                source_ip,
+                filter,
+                probability,
+                action,
+            } => {
+                let comment = rule_key("-s", source_ip, filter, probability, action);
+                format!("{}; {}", ensure_table(), delete_all("input", &comment))
+            }
+            IpTablesCommands::DropFrom {
+                source_ip,
+                filter,
+                probability,
+                action,
+            } => {
+                let comment = rule_key("-s", source_ip, filter, probability, action);
+                format!(
+                    "{}; {}",
+                    ensure_table(),
+                    append_once(
+                        "input",
+                        &drop_rule("saddr", source_ip, filter, probability, action),
+                        &comment
+                    )
+                )
+            }
+            IpTablesCommands::RestoreTo {
+                destination_ip,
+                filter,
+                probability,
+                action,
+            } => {
+                let comment = rule_key("-d", destination_ip, filter, probability, action);
+                format!("{}; {}", ensure_table(), delete_all("output", &comment))
+            }
+            IpTablesCommands::DropTo {
+                destination_ip,
+                filter,
+                probability,
+                action,
+            } => {
+                let comment = rule_key("-d", destination_ip, filter, probability, action);
+                format!(
+                    "{}; {}",
+                    ensure_table(),
+                    append_once(
+                        "output",
+                        &drop_rule("daddr", destination_ip, filter, probability, action),
+                        &comment
+                    )
+                )
+            }
+            IpTablesCommands::Get | IpTablesCommands::Save => {
+                format!("{}; /usr/sbin/nft list table inet {TABLE}", ensure_table())
+            }
+            IpTablesCommands::Load { rules } => {
+                format!(
+                    "/usr/sbin/nft delete table inet {TABLE} 2>/dev/null; printf '%s\\n' {} | /usr/sbin/nft -f -",
+                    super::ip::quote(rules.trim())
+                )
+            }
+            IpTablesCommands::Batch(commands) => super::ip::sum_changes(
+                commands
+                    .iter()
+                    .map(|command| NfTablesCommands(command.clone()).script()),
+            ),
+        }
+    }
+}
+
+impl super::Command for NfTablesCommands {
+    fn build<'session>(&self, session: &'session openssh::Session) -> openssh::Command<'session> {
+        super::sudo_script(session, self.script())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_drop_rule() {
+        let filter = Filter {
+            protocol: Some(Protocol::Tcp),
+            ports: Some("9000".parse().unwrap()),
+        };
+        assert_eq!(
+            drop_rule(
+                "saddr",
+                &"192.168.192.4".parse().unwrap(),
+                &filter,
+                &Some(0.2),
+                &Action::Drop
+            ),
+            "ip saddr 192.168.192.4 tcp dport 9000-9000 numgen random mod 1000000 < 200000 drop"
+        );
+        assert_eq!(
+            drop_rule(
+                "daddr",
+                &"fd00::4".parse().unwrap(),
+                &Filter {
+                    protocol: Some(Protocol::Icmp),
+                    ports: None
+                },
+                &None,
+                &Action::Reject
+            ),
+            "ip6 daddr fd00::4 meta l4proto ipv6-icmp reject"
+        );
+    }
+
+    #[test]
+    fn test_drop_from_script() {
+        let script = NfTablesCommands(IpTablesCommands::DropFrom {
+            source_ip: "192.168.192.4".parse().unwrap(),
+            filter: Filter::default(),
+            probability: None,
+            action: Action::Drop,
+        })
+        .script();
+        assert!(script.ends_with(
+            "else /usr/sbin/nft 'add rule inet partition_sim input ip saddr 192.168.192.4 drop \
+             comment \"-s 192.168.192.4 - - - drop\"' && echo 1; fi"
+        ));
+    }
+
+    #[test]
+    fn test_longest_rule_key() {
+        let (ip_addr, filter, probability, action) = (
+            "ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff".parse().unwrap(),
+            Filter {
+                protocol: Some(Protocol::Udp),
+                ports: Some("65534:65535".parse().unwrap()),
+            },
+            Some(0.123456789012345),
+            Action::Reject,
+        );
+        let spec = super::super::ip::drop_rule("-d", &ip_addr, &filter, &probability, &action);
+        assert!(spec.join(" ").len() > 128);
+
+        let key = rule_key("-d", &ip_addr, &filter, &probability, &action);
+        assert!(key.len() <= 128);
+        assert_eq!(spec_from_comment(&key), Some(spec.join(" ")));
+        let script = NfTablesCommands(IpTablesCommands::DropTo {
+            destination_ip: ip_addr,
+            filter,
+            probability,
+            action,
+        })
+        .script();
+        assert!(script.contains(&format!("comment \"{}\"", key)));
+
+        // Rules added before the keys carry the specification itself.
+        assert_eq!(spec_from_comment("-s 192.168.192.4 -j DROP"), None);
+    }
+}
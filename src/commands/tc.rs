@@ -0,0 +1,299 @@
+use std::net::IpAddr;
+
+use serde::{Deserialize, Serialize};
+
+use super::Commands;
+
+/// The network interface that traffic shaping is applied to on every peer.
+/// Docker attaches each container to its network through `eth0`.
+const DEVICE: &str = "eth0";
+
+/// The rate given to htb classes that aren't meant to throttle anything.
+const UNLIMITED_RATE: &str = "10gbit";
+
+/// Traffic control (`tc`) commands shape the traffic that leaves the target node
+/// instead of dropping it outright.
+///
+/// Every destination gets its own htb class under a shared root qdisc on the
+/// target node, with a `netem` qdisc attached to it. A `u32` filter on the destination
+/// address steers the traffic into that class, while all other traffic falls through
+/// to the default (unshaped) class. The `class` is the minor number of that htb class
+/// (and the priority of its filter), which must be different for every destination
+/// and at least 2, since 1 is taken by the default class.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum TcCommands {
+    /// Remove all traffic shaping on the target node.
+    Restore,
+    /// Impair all outbound traffic from the target node towards a given destination IP
+    /// with `netem`. This replaces any impairments previously set for that destination.
+    NetemTo {
+        destination_ip: IpAddr,
+        class: u16,
+        netem: Netem,
+    },
+    /// Remove the `netem` impairments on the outbound traffic from the target node
+    /// towards a given destination IP.
+    ClearNetemTo { destination_ip: IpAddr, class: u16 },
+    /// Cap the throughput of all outbound traffic from the target node towards a given
+    /// destination IP at `rate_kbit` kilobits per second.
+    ThrottleTo {
+        destination_ip: IpAddr,
+        class: u16,
+        rate_kbit: u32,
+    },
+    /// Remove the cap on the throughput of the outbound traffic from the target node
+    /// towards a given destination IP.
+    ClearThrottleTo { destination_ip: IpAddr, class: u16 },
+}
+
+/// The impairments a `netem` qdisc applies to the traffic it sees.
+/// All percentages are between 0 and 100.
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub struct Netem {
+    /// A fixed latency (in milliseconds) added to every packet.
+    #[serde(default)]
+    pub delay_ms: u32,
+    /// A random variation (in milliseconds) around the fixed latency.
+    #[serde(default)]
+    pub jitter_ms: u32,
+    /// The percentage of packets that are sent twice.
+    pub duplicate: Option<f64>,
+    /// The percentage of packets that are sent right away, jumping ahead
+    /// of the delayed ones. Requires a delay.
+    pub reorder: Option<f64>,
+    /// The percentage of packets that get a random bit flipped.
+    pub corrupt: Option<f64>,
+}
+
+impl Netem {
+    pub fn validate(&self) -> crate::Result<()> {
+        for (name, percentage) in [
+            ("duplicate", self.duplicate),
+            ("reorder", self.reorder),
+            ("corrupt", self.corrupt),
+        ] {
+            if let Some(percentage) = percentage {
+                if !(percentage > 0.0 && percentage <= 100.0) {
+                    return Err(crate::Error::InvalidParameter(format!(
+                        "{} must be a percentage in (0, 100], got {}",
+                        name, percentage
+                    )));
+                }
+            }
+        }
+        if self.reorder.is_some() && self.delay_ms == 0 {
+            return Err(crate::Error::InvalidParameter(
+                "reorder requires a delay".into(),
+            ));
+        }
+        if self.jitter_ms > 0 && self.delay_ms == 0 {
+            return Err(crate::Error::InvalidParameter(
+                "jitter requires a delay".into(),
+            ));
+        }
+        if *self == Netem::default() {
+            return Err(crate::Error::InvalidParameter(
+                "at least one netem impairment must be given".into(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// The options passed to `tc qdisc ... netem`.
+    fn options(&self) -> String {
+        let mut options = vec![];
+        if self.delay_ms > 0 {
+            options.push(format!("delay {}ms", self.delay_ms));
+            if self.jitter_ms > 0 {
+                options.push(format!("{}ms", self.jitter_ms));
+            }
+        }
+        if let Some(duplicate) = self.duplicate {
+            options.push(format!("duplicate {}%", duplicate));
+        }
+        if let Some(reorder) = self.reorder {
+            options.push(format!("reorder {}%", reorder));
+        }
+        if let Some(corrupt) = self.corrupt {
+            options.push(format!("corrupt {}%", corrupt));
+        }
+        options.join(" ")
+    }
+}
+
+impl From<TcCommands> for Commands {
+    fn from(command: TcCommands) -> Self {
+        Self::Tc(command)
+    }
+}
+
+/// The `u32` match that selects all the traffic towards the given address.
+fn destination_match(ip_addr: &IpAddr) -> String {
+    match ip_addr {
+        IpAddr::V4(addr) => format!("protocol ip u32 match ip dst {}/32", addr),
+        IpAddr::V6(addr) => format!("protocol ipv6 u32 match ip6 dst {}/128", addr),
+    }
+}
+
+/// Make sure the root qdisc and classes exist and traffic towards the given
+/// address is steered into its own class. Every step but the last is allowed to
+/// fail if its object already exists, so that shaping can be layered onto a class.
+fn set_up_class(ip_addr: &IpAddr, minor: u16) -> String {
+    [
+        format!("/usr/sbin/tc qdisc add dev {DEVICE} root handle 1: htb default 1 2>/dev/null"),
+        format!(
+            "/usr/sbin/tc class add dev {DEVICE} parent 1: classid 1:1 htb rate {UNLIMITED_RATE} 2>/dev/null"
+        ),
+        format!(
+            "/usr/sbin/tc class add dev {DEVICE} parent 1: classid 1:{minor:x} htb rate {UNLIMITED_RATE} 2>/dev/null"
+        ),
+        format!("/usr/sbin/tc filter del dev {DEVICE} parent 1: prio {minor} 2>/dev/null"),
+        format!(
+            "/usr/sbin/tc filter add dev {DEVICE} parent 1: prio {minor} {} flowid 1:{minor:x}",
+            destination_match(ip_addr)
+        ),
+    ]
+    .join("; ")
+}
+
+/// Set the rate of the class that holds the traffic towards a destination.
+fn set_rate(minor: u16, rate: &str) -> String {
+    format!(
+        "/usr/sbin/tc class change dev {DEVICE} parent 1: classid 1:{minor:x} htb rate {rate} ceil {rate}"
+    )
+}
+
+impl TcCommands {
+    /// The shell script that gets run (as root) on the target node.
+    pub fn script(&self) -> String {
+        match self {
+            Self::Restore => {
+                format!("/usr/sbin/tc qdisc del dev {DEVICE} root 2>/dev/null; true")
+            }
+            Self::NetemTo {
+                destination_ip,
+                class: minor,
+                netem,
+            } => {
+                format!(
+                    "{} && /usr/sbin/tc qdisc replace dev {DEVICE} parent 1:{minor:x} handle {minor:x}: netem {}",
+                    set_up_class(destination_ip, *minor),
+                    netem.options(),
+                )
+            }
+            Self::ClearNetemTo { class: minor, .. } => {
+                format!("/usr/sbin/tc qdisc del dev {DEVICE} parent 1:{minor:x} 2>/dev/null; true")
+            }
+            Self::ThrottleTo {
+                destination_ip,
+                class: minor,
+                rate_kbit,
+            } => {
+                format!(
+                    "{} && {}",
+                    set_up_class(destination_ip, *minor),
+                    set_rate(*minor, &format!("{}kbit", rate_kbit)),
+                )
+            }
+            Self::ClearThrottleTo { class: minor, .. } => {
+                format!("{} 2>/dev/null; true", set_rate(*minor, UNLIMITED_RATE))
+            }
+        }
+    }
+}
+
+impl super::Command for TcCommands {
+    fn build<'session>(&self, session: &'session openssh::Session) -> openssh::Command<'session> {
+        super::sudo_script(session, self.script())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_classes_dont_collide() {
+        let netem = Netem {
+            delay_ms: 100,
+            ..Default::default()
+        };
+        let first = TcCommands::NetemTo {
+            destination_ip: "10.0.0.1".parse().unwrap(),
+            class: 2,
+            netem: netem.clone(),
+        }
+        .script();
+        let second = TcCommands::NetemTo {
+            destination_ip: "10.0.0.2".parse().unwrap(),
+            class: 3,
+            netem,
+        }
+        .script();
+        assert!(first.contains("prio 2 protocol ip u32 match ip dst 10.0.0.1/32 flowid 1:2"));
+        assert!(second.contains("filter del dev eth0 parent 1: prio 3 "));
+        assert!(second.contains("prio 3 protocol ip u32 match ip dst 10.0.0.2/32 flowid 1:3"));
+        assert!(!second.contains("prio 2 "));
+    }
+
+    #[test]
+    fn test_netem_to_script() {
+        let script = TcCommands::NetemTo {
+            destination_ip: "192.168.192.4".parse().unwrap(),
+            class: 0xc004,
+            netem: Netem {
+                delay_ms: 200,
+                jitter_ms: 20,
+                ..Default::default()
+            },
+        }
+        .script();
+        assert!(script.contains("match ip dst 192.168.192.4/32 flowid 1:c004"));
+        assert!(script.ends_with("parent 1:c004 handle c004: netem delay 200ms 20ms"));
+    }
+
+    #[test]
+    fn test_netem_options() {
+        let netem = Netem {
+            delay_ms: 10,
+            jitter_ms: 0,
+            duplicate: Some(1.0),
+            reorder: Some(25.0),
+            corrupt: Some(0.1),
+        };
+        netem.validate().unwrap();
+        assert_eq!(
+            netem.options(),
+            "delay 10ms duplicate 1% reorder 25% corrupt 0.1%"
+        );
+    }
+
+    #[test]
+    fn test_netem_validate() {
+        assert!(Netem::default().validate().is_err());
+        assert!(Netem {
+            reorder: Some(25.0),
+            ..Default::default()
+        }
+        .validate()
+        .is_err());
+        assert!(Netem {
+            corrupt: Some(120.0),
+            ..Default::default()
+        }
+        .validate()
+        .is_err());
+    }
+
+    #[test]
+    fn test_throttle_to_script() {
+        let script = TcCommands::ThrottleTo {
+            destination_ip: "192.168.192.4".parse().unwrap(),
+            class: 0xc004,
+            rate_kbit: 512,
+        }
+        .script();
+        assert!(script.contains("match ip dst 192.168.192.4/32 flowid 1:c004"));
+        assert!(script.ends_with("classid 1:c004 htb rate 512kbit ceil 512kbit"));
+    }
+}
@@ -40,30 +40,40 @@ pub fn query_consul_for_peers(
         })
         .collect();
 
-    let dns_names = dns_names_and_ports
-        .iter()
-        .map(|&(dns_name, _)| dns_name)
-        .collect::<Vec<_>>();
+    dns_names_and_ports
+        .into_iter()
+        .map(|(dns_name, port)| {
+            let ip_addr = resolve(dns_addr, dns_port, dns_name)?;
+            let port = port
+                .parse::<u16>()
+                .map_err(|_| crate::Error::ConsulError(format!("invalid port: {}", port)))?;
+            Ok((ip_addr, port))
+        })
+        .collect()
+}
 
+/// Resolve the address a peer registered with, preferring its `A` record
+/// over its `AAAA` record so that IPv6-only networks work too.
+fn resolve(dns_addr: &str, dns_port: u16, dns_name: &str) -> crate::Result<IpAddr> {
     let output = Command::new("dig")
         .arg("+short")
         .arg(format!("@{}", dns_addr))
         .arg("-p")
         .arg(format!("{}", dns_port))
-        .args(&dns_names)
+        .arg(dns_name)
+        .arg("A")
+        .arg(dns_name)
+        .arg("AAAA")
         .output()?;
 
     let output = String::from_utf8_lossy(&output.stdout);
 
-    Ok(output
+    output
         .lines()
-        .map(|line| line.to_string())
-        .zip(dns_names_and_ports.into_iter().map(|(_, port)| port))
-        .map(|(ip_addr_raw, port)| {
-            let ip_addr = ip_addr_raw.parse::<IpAddr>().unwrap();
-            (ip_addr, port.parse::<u16>().unwrap())
+        .find_map(|line| line.trim().parse::<IpAddr>().ok())
+        .ok_or_else(|| {
+            crate::Error::ConsulError(format!("no A or AAAA record found for {}", dns_name))
         })
-        .collect())
 }
 
 #[cfg(test)]
@@ -78,8 +88,7 @@ mod tests {
     pub fn stuff() {
         let res = query_consul_for_peers("127.0.0.1", 8600, "test-node-base").unwrap();
         println!("{:#?}", res);
-        let observed: HashSet<(IpAddr, u16)> =
-            std::collections::HashSet::from_iter(res.into_iter());
+        let observed: HashSet<(IpAddr, u16)> = std::collections::HashSet::from_iter(res);
         let expected = std::collections::HashSet::from_iter(vec![
             ("192.168.192.4".parse().unwrap(), 9001),
             ("192.168.192.6".parse().unwrap(), 9001),
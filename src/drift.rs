@@ -0,0 +1,122 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::commands::{Command, IpTablesCommands};
+use crate::peer::Peer;
+use crate::rules::{parse_rules, Rule};
+
+/// The difference between the rules a peer should have, given the faults the
+/// supervisor applied, and the ones it actually has.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Drift {
+    pub peer: Uuid,
+    /// The rules of the applied faults that the peer lost, e.g. because it restarted.
+    pub missing: Vec<Rule>,
+    /// The rules the peer has that no applied fault accounts for, e.g. because someone
+    /// added them by hand.
+    pub unexpected: Vec<Rule>,
+}
+
+impl Drift {
+    /// Compare the rules a peer should have with the ones it actually has.
+    pub fn between(peer: Uuid, desired: &[Rule], actual: &[Rule]) -> Self {
+        let missing = desired
+            .iter()
+            .filter(|rule| !actual.iter().any(|other| rule.same_as(other)))
+            .cloned()
+            .collect();
+        let unexpected = actual
+            .iter()
+            .filter(|rule| !desired.iter().any(|other| rule.same_as(other)))
+            .cloned()
+            .collect();
+        Self {
+            peer,
+            missing,
+            unexpected,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.missing.is_empty() && self.unexpected.is_empty()
+    }
+}
+
+/// Everything it takes to check a peer for drift, captured from the supervisor so that
+/// the check doesn't hold on to it while waiting on SSH.
+#[derive(Debug, Clone)]
+pub struct DriftCheck {
+    peer: Peer,
+    desired: Vec<Rule>,
+    /// The ids of all the peers by address, to resolve the addresses in the actual rules.
+    peer_ids: HashMap<IpAddr, Uuid>,
+}
+
+impl DriftCheck {
+    pub fn new(peer: Peer, desired: Vec<Rule>, peer_ids: HashMap<IpAddr, Uuid>) -> Self {
+        Self {
+            peer,
+            desired,
+            peer_ids,
+        }
+    }
+
+    pub fn peer_id(&self) -> Uuid {
+        self.peer.id
+    }
+
+    /// Fetch the rules the peer actually has and compare them with the desired ones.
+    /// The check reuses the supervisor's session to the peer, or opens its own.
+    pub async fn run(mut self) -> crate::Result<Drift> {
+        self.peer.connect().await?;
+        let session = self
+            .peer
+            .session
+            .as_deref()
+            .ok_or(crate::Error::SessionUninitialized)?;
+        let output = self
+            .peer
+            .translate(IpTablesCommands::Get.into())
+            .build(session)
+            .output()
+            .await?;
+        if !output.status.success() {
+            return Err(output.into());
+        }
+        let mut actual = parse_rules(&String::from_utf8_lossy(&output.stdout))?;
+        for rule in actual.iter_mut() {
+            rule.resolve_peers(|ip| self.peer_ids.get(&ip).copied());
+        }
+        Ok(Drift::between(self.peer.id, &self.desired, &actual))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_between() {
+        let kept = Rule::from_spec("-s 192.168.192.4 -j DROP").unwrap();
+        let lost = Rule::from_spec("-d 192.168.192.5 -p udp -j REJECT").unwrap();
+        let extra = Rule::from_spec("-s 192.168.192.6 -j DROP").unwrap();
+        let peer = Uuid::new_v4();
+
+        let drift = Drift::between(
+            peer,
+            &[kept.clone(), lost.clone()],
+            &[
+                Rule::from_spec("-s 192.168.192.4/32 -j DROP").unwrap(),
+                extra.clone(),
+            ],
+        );
+        assert_eq!(drift.missing, vec![lost]);
+        assert_eq!(drift.unexpected, vec![extra]);
+
+        let kept = [kept];
+        assert!(Drift::between(peer, &kept, &kept).is_empty());
+    }
+}
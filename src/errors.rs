@@ -1,6 +1,6 @@
 use std::process::Output;
 
-use axum::{http::StatusCode, response::IntoResponse};
+use axum::{http::StatusCode, response::IntoResponse, Extension};
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -21,6 +21,10 @@ pub enum PartitionSimError {
     SshCopyIdFailed,
     #[error("Other error: {0}")]
     Other(String),
+    #[error("snapshot not found: {0}")]
+    SnapshotNotFound(String),
+    #[error("Invalid parameter: {0}")]
+    InvalidParameter(String),
     #[error("Couldn't parse Uuid: {0}")]
     UuidParseError(#[from] uuid::Error),
     #[error(
@@ -45,9 +49,18 @@ impl From<Output> for PartitionSimError {
     }
 }
 
+/// The message of the error a response was made from, so that middleware can report it.
+#[derive(Debug, Clone)]
+pub struct ErrorMessage(pub String);
+
 impl IntoResponse for PartitionSimError {
     fn into_response(self) -> axum::response::Response {
         let msg = format!("{}", self);
-        (StatusCode::INTERNAL_SERVER_ERROR, msg).into_response()
+        let status_code = match self {
+            PartitionSimError::InvalidParameter(_) => StatusCode::BAD_REQUEST,
+            PartitionSimError::SnapshotNotFound(_) => StatusCode::NOT_FOUND,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        (status_code, Extension(ErrorMessage(msg.clone())), msg).into_response()
     }
 }
@@ -0,0 +1,425 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Write;
+use std::net::IpAddr;
+use std::path::Path;
+use std::process::Output;
+use std::str::FromStr;
+use std::time::SystemTime;
+
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::commands::{Commands, Netem};
+use crate::faults::Partition;
+use crate::Supervisor;
+
+/// A command the supervisor ran on a peer, and how it went.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Event {
+    #[serde(with = "humantime_serde")]
+    pub timestamp: SystemTime,
+    pub peer_id: Uuid,
+    pub peer_ip: IpAddr,
+    /// The addresses of all the peers, so that the addresses in the command can be
+    /// mapped onto another cluster by position.
+    pub cluster: Vec<IpAddr>,
+    pub command: Commands,
+    /// The exit status of the command, if it ran to completion.
+    pub exit_status: Option<i32>,
+    pub stdout: String,
+    pub stderr: String,
+    /// Why the command couldn't be run at all, e.g. the peer was unreachable.
+    #[serde(default)]
+    pub error: Option<String>,
+}
+
+impl Event {
+    pub fn new(
+        peer_id: Uuid,
+        peer_ip: IpAddr,
+        cluster: Vec<IpAddr>,
+        command: Commands,
+        output: &Result<Output, openssh::Error>,
+    ) -> Self {
+        let (exit_status, stdout, stderr, error) = match output {
+            Ok(output) => (
+                output.status.code(),
+                String::from_utf8_lossy(&output.stdout).into_owned(),
+                String::from_utf8_lossy(&output.stderr).into_owned(),
+                None,
+            ),
+            Err(err) => (None, String::new(), String::new(), Some(err.to_string())),
+        };
+        Self {
+            timestamp: SystemTime::now(),
+            peer_id,
+            peer_ip,
+            cluster,
+            command,
+            exit_status,
+            stdout,
+            stderr,
+            error,
+        }
+    }
+}
+
+/// Something that happened to the cluster, as streamed to whoever follows the supervisor.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ClusterEvent {
+    #[serde(with = "humantime_serde")]
+    pub timestamp: SystemTime,
+    #[serde(flatten)]
+    pub kind: ClusterEventKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum ClusterEventKind {
+    /// The supervisor (re)loaded the peers, given by id along with their address.
+    ClusterLoaded {
+        peers: HashMap<Uuid, IpAddr>,
+    },
+    Partition {
+        source: Uuid,
+        target: Uuid,
+        #[serde(flatten)]
+        partition: Partition,
+    },
+    Heal {
+        source: Uuid,
+        target: Uuid,
+        #[serde(flatten)]
+        partition: Partition,
+    },
+    Netem {
+        source: Uuid,
+        target: Uuid,
+        #[serde(flatten)]
+        netem: Netem,
+    },
+    ClearNetem {
+        source: Uuid,
+        target: Uuid,
+    },
+    Throttle {
+        source: Uuid,
+        target: Uuid,
+        rate_kbit: u32,
+    },
+    ClearThrottle {
+        source: Uuid,
+        target: Uuid,
+    },
+    SnapshotApplied {
+        name: String,
+    },
+    Restore,
+    /// Something went wrong, e.g. a request failed or a fault couldn't be healed in time.
+    Error {
+        message: String,
+    },
+}
+
+impl From<ClusterEventKind> for ClusterEvent {
+    fn from(kind: ClusterEventKind) -> Self {
+        Self {
+            timestamp: SystemTime::now(),
+            kind,
+        }
+    }
+}
+
+/// An append-only log of [`Event`]s, one JSON object per line.
+#[derive(Debug)]
+pub struct EventLog {
+    file: File,
+}
+
+impl EventLog {
+    /// Open the log at the given path, creating it if needed. Events get appended to
+    /// the ones already there.
+    pub fn open(path: impl AsRef<Path>) -> crate::Result<Self> {
+        let file = File::options().create(true).append(true).open(path)?;
+        Ok(Self { file })
+    }
+
+    pub fn record(&mut self, event: &Event) -> crate::Result<()> {
+        let mut line = serde_json::to_string(event)
+            .map_err(|err| crate::Error::Other(format!("couldn't serialize event: {}", err)))?;
+        line.push('\n');
+        self.file.write_all(line.as_bytes())?;
+        Ok(())
+    }
+}
+
+/// Read the events in a log written by an [`EventLog`].
+pub fn read_log(path: impl AsRef<Path>) -> crate::Result<Vec<Event>> {
+    std::fs::read_to_string(path)?
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(index, line)| {
+            serde_json::from_str(line).map_err(|err| {
+                crate::Error::InvalidParameter(format!(
+                    "invalid event on line {}: {}",
+                    index + 1,
+                    err
+                ))
+            })
+        })
+        .collect()
+}
+
+/// How the peers in a log map onto the peers of the cluster it gets replayed on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PeerMapping {
+    /// With the peers ordered by address, the n-th peer then is the n-th peer now.
+    /// The addresses in the commands are translated accordingly.
+    #[default]
+    Position,
+    /// The peers have the same addresses as before.
+    Ip,
+}
+
+impl FromStr for PeerMapping {
+    type Err = crate::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "position" => Ok(Self::Position),
+            "ip" => Ok(Self::Ip),
+            _ => Err(crate::Error::InvalidParameter(format!(
+                "unknown peer mapping: {}. Expected one of: position, ip",
+                s
+            ))),
+        }
+    }
+}
+
+/// Replace the addresses found in the text with the ones they map to.
+fn remap_text(text: &str, ips: &HashMap<IpAddr, IpAddr>) -> String {
+    let is_address_char = |c: char| c.is_ascii_hexdigit() || c == '.' || c == ':';
+    let mut remapped = String::with_capacity(text.len());
+    let mut rest = text;
+    while !rest.is_empty() {
+        let end = rest.find(|c| !is_address_char(c)).unwrap_or(rest.len());
+        let (token, after) = rest.split_at(end);
+        match token.parse::<IpAddr>().ok().and_then(|ip| ips.get(&ip)) {
+            Some(ip) => remapped.push_str(&ip.to_string()),
+            None => remapped.push_str(token),
+        }
+        let separators = after.find(is_address_char).unwrap_or(after.len());
+        remapped.push_str(&after[..separators]);
+        rest = &after[separators..];
+    }
+    remapped
+}
+
+fn remap_value(value: &mut serde_json::Value, ips: &HashMap<IpAddr, IpAddr>) {
+    match value {
+        serde_json::Value::String(text) => *text = remap_text(text, ips),
+        serde_json::Value::Array(values) => values.iter_mut().for_each(|v| remap_value(v, ips)),
+        serde_json::Value::Object(map) => map.values_mut().for_each(|v| remap_value(v, ips)),
+        _ => {}
+    }
+}
+
+/// Replace the addresses anywhere in the command (including in dumped rules) with the ones they map to.
+fn remap_command(command: &Commands, ips: &HashMap<IpAddr, IpAddr>) -> crate::Result<Commands> {
+    let invalid =
+        |err: serde_json::Error| crate::Error::Other(format!("couldn't remap command: {}", err));
+    let mut value = serde_json::to_value(command).map_err(invalid)?;
+    remap_value(&mut value, ips);
+    serde_json::from_value(value).map_err(invalid)
+}
+
+impl Supervisor {
+    /// Run the command of a logged event again, on the peer it maps to in this cluster.
+    pub async fn replay(&mut self, event: &Event, mapping: PeerMapping) -> crate::Result<Output> {
+        let (peer_id, command) = self.replay_target(event, mapping)?;
+        self.execute(peer_id, command).await
+    }
+
+    /// The peer the command of a logged event goes to in this cluster, and the command
+    /// with its addresses translated to this cluster's.
+    fn replay_target(
+        &self,
+        event: &Event,
+        mapping: PeerMapping,
+    ) -> crate::Result<(Uuid, Commands)> {
+        let unknown_peer = || {
+            crate::Error::InvalidParameter(format!(
+                "no peer maps to {} in this cluster",
+                event.peer_ip
+            ))
+        };
+        let (peer_id, command) = match mapping {
+            PeerMapping::Position => {
+                if event.cluster.len() > self.get_peer_ids().len() {
+                    return Err(crate::Error::InvalidParameter(format!(
+                        "the log has {} peers, but this cluster only has {}",
+                        event.cluster.len(),
+                        self.get_peer_ids().len()
+                    )));
+                }
+                // Older logs have the peers in the order Consul happened to answer with.
+                let mut cluster = event.cluster.clone();
+                cluster.sort();
+                let ips = cluster
+                    .iter()
+                    .zip(self.get_peer_ids())
+                    .map(|(ip, peer_id)| Ok((*ip, self.get_peer(*peer_id)?.ip_addr)))
+                    .collect::<crate::Result<HashMap<_, _>>>()?;
+                let index = cluster
+                    .iter()
+                    .position(|ip| *ip == event.peer_ip)
+                    .ok_or_else(unknown_peer)?;
+                (
+                    self.get_peer_ids()[index],
+                    remap_command(&event.command, &ips)?,
+                )
+            }
+            PeerMapping::Ip => (
+                self.find_peer_by_ip(event.peer_ip)
+                    .ok_or_else(unknown_peer)?,
+                event.command.clone(),
+            ),
+        };
+        Ok((peer_id, command))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::{Action, ConntrackCommands, Filter, IpTablesCommands};
+
+    #[test]
+    fn test_remap_command() {
+        let ips = HashMap::from([
+            (
+                "10.0.0.1".parse().unwrap(),
+                "192.168.192.2".parse().unwrap(),
+            ),
+            (
+                "10.0.0.2".parse().unwrap(),
+                "192.168.192.3".parse().unwrap(),
+            ),
+        ]);
+        let command =
+            Commands::IpTables(IpTablesCommands::Batch(vec![IpTablesCommands::DropFrom {
+                source_ip: "10.0.0.1".parse().unwrap(),
+                filter: Filter::default(),
+                probability: None,
+                action: Action::Drop,
+            }]));
+        assert_eq!(
+            remap_command(&command, &ips).unwrap(),
+            Commands::IpTables(IpTablesCommands::Batch(vec![IpTablesCommands::DropFrom {
+                source_ip: "192.168.192.2".parse().unwrap(),
+                filter: Filter::default(),
+                probability: None,
+                action: Action::Drop,
+            }]))
+        );
+
+        let command = Commands::IpTables(IpTablesCommands::Load {
+            rules: "-A PARTITION_SIM -s 10.0.0.2/32 -j DROP\n-A PARTITION_SIM -s 10.0.0.20/32 -j DROP\n"
+                .into(),
+        });
+        assert_eq!(
+            remap_command(&command, &ips).unwrap(),
+            Commands::IpTables(IpTablesCommands::Load {
+                rules: "-A PARTITION_SIM -s 192.168.192.3/32 -j DROP\n-A PARTITION_SIM -s 10.0.0.20/32 -j DROP\n"
+                    .into(),
+            })
+        );
+    }
+
+    #[test]
+    fn test_cluster_event_json() {
+        let (source, target) = (Uuid::new_v4(), Uuid::new_v4());
+        let (sender, mut receiver) = tokio::sync::broadcast::channel(4);
+        let supervisor = Supervisor::default().with_cluster_events(sender);
+        supervisor.notify(ClusterEventKind::Partition {
+            source,
+            target,
+            partition: Partition::default(),
+        });
+        supervisor.notify(ClusterEventKind::Restore);
+
+        let json = serde_json::to_value(receiver.try_recv().unwrap()).unwrap();
+        assert_eq!(json["event"], "partition");
+        assert_eq!(json["source"], source.to_string());
+        assert_eq!(json["target"], target.to_string());
+        assert_eq!(json["direction"], "inbound");
+        assert!(json["timestamp"].is_string());
+        let json = serde_json::to_value(receiver.try_recv().unwrap()).unwrap();
+        assert_eq!(json["event"], "restore");
+    }
+
+    #[test]
+    fn test_replay_target_by_position() {
+        let supervisor = Supervisor::new(vec![
+            crate::Peer::new("192.168.192.3".parse().unwrap(), None, None),
+            crate::Peer::new("192.168.192.2".parse().unwrap(), None, None),
+        ]);
+        let event = Event {
+            timestamp: SystemTime::UNIX_EPOCH,
+            peer_id: Uuid::new_v4(),
+            peer_ip: "10.0.0.2".parse().unwrap(),
+            // In the order Consul answered with, back then.
+            cluster: vec!["10.0.0.2".parse().unwrap(), "10.0.0.1".parse().unwrap()],
+            command: ConntrackCommands::FlushFrom {
+                source_ip: "10.0.0.1".parse().unwrap(),
+            }
+            .into(),
+            exit_status: Some(0),
+            stdout: "".into(),
+            stderr: "".into(),
+            error: None,
+        };
+        let (peer_id, command) = supervisor
+            .replay_target(&event, PeerMapping::Position)
+            .unwrap();
+        assert_eq!(
+            supervisor.get_peer(peer_id).unwrap().ip_addr,
+            "192.168.192.3".parse::<IpAddr>().unwrap()
+        );
+        assert_eq!(
+            command,
+            ConntrackCommands::FlushFrom {
+                source_ip: "192.168.192.2".parse().unwrap()
+            }
+            .into()
+        );
+        assert!(supervisor.replay_target(&event, PeerMapping::Ip).is_err());
+    }
+
+    #[test]
+    fn test_log_round_trip() {
+        let path = std::env::temp_dir().join(format!("partition-sim-{}.jsonl", Uuid::new_v4()));
+        let event = Event {
+            timestamp: SystemTime::UNIX_EPOCH,
+            peer_id: Uuid::new_v4(),
+            peer_ip: "10.0.0.1".parse().unwrap(),
+            cluster: vec!["10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap()],
+            command: ConntrackCommands::FlushFrom {
+                source_ip: "10.0.0.2".parse().unwrap(),
+            }
+            .into(),
+            exit_status: Some(0),
+            stdout: "".into(),
+            stderr: "0 flow entries have been deleted.\n".into(),
+            error: None,
+        };
+        let mut log = EventLog::open(&path).unwrap();
+        log.record(&event).unwrap();
+        log.record(&event).unwrap();
+        let events = read_log(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(events, vec![event.clone(), event]);
+    }
+}
@@ -0,0 +1,604 @@
+use std::net::IpAddr;
+use std::time::{Duration, SystemTime};
+
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::commands::{
+    Action, ConntrackCommands, Direction, Filter, IpTablesCommands, Netem, PortRange, Protocol,
+};
+
+/// How the traffic between two peers gets cut.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+pub struct Partition {
+    /// Only drop each packet with this probability (between 0 and 1)
+    /// instead of dropping all of them.
+    pub probability: Option<f64>,
+    /// Which way to cut the traffic between the source and the target peer.
+    #[serde(default)]
+    pub direction: Direction,
+    /// Only cut the traffic of this protocol.
+    pub protocol: Option<Protocol>,
+    /// Only cut the traffic to this destination port (or range of ports, like `9000:9010`).
+    /// Requires the `tcp` or `udp` protocol.
+    pub port: Option<PortRange>,
+    /// Whether the packets should vanish (`drop`) or get rejected (`reject`).
+    #[serde(default)]
+    pub action: Action,
+}
+
+impl Partition {
+    pub fn filter(&self) -> Filter {
+        Filter {
+            protocol: self.protocol,
+            ports: self.port,
+        }
+    }
+
+    pub fn validate(&self) -> crate::Result<()> {
+        self.filter().validate()?;
+        if let Some(probability) = self.probability {
+            if !(probability > 0.0 && probability <= 1.0) {
+                return Err(crate::Error::InvalidParameter(format!(
+                    "probability must be in (0, 1], got {}",
+                    probability
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether both partitions cut the same traffic, regardless of the direction.
+    fn same_traffic(&self, other: &Partition) -> bool {
+        self.probability == other.probability
+            && self.protocol == other.protocol
+            && self.port == other.port
+            && self.action == other.action
+    }
+
+    /// The commands the target peer needs to run to drop the traffic from/to the source peer.
+    pub fn drop_commands(&self, source_ip: IpAddr) -> Vec<IpTablesCommands> {
+        let mut commands = vec![];
+        if self.direction.inbound() {
+            commands.push(IpTablesCommands::DropFrom {
+                source_ip,
+                filter: self.filter(),
+                probability: self.probability,
+                action: self.action,
+            });
+        }
+        if self.direction.outbound() {
+            commands.push(IpTablesCommands::DropTo {
+                destination_ip: source_ip,
+                filter: self.filter(),
+                probability: self.probability,
+                action: self.action,
+            });
+        }
+        commands
+    }
+
+    /// The commands the target peer needs to run to forget its connections from/to the source peer.
+    pub fn conntrack_commands(&self, source_ip: IpAddr) -> Vec<ConntrackCommands> {
+        let mut commands = vec![];
+        if self.direction.inbound() {
+            commands.push(ConntrackCommands::FlushFrom { source_ip });
+        }
+        if self.direction.outbound() {
+            commands.push(ConntrackCommands::FlushTo {
+                destination_ip: source_ip,
+            });
+        }
+        commands
+    }
+
+    /// The commands the target peer needs to run to accept the traffic from/to the source peer again.
+    pub fn restore_commands(&self, source_ip: IpAddr) -> Vec<IpTablesCommands> {
+        let mut commands = vec![];
+        if self.direction.inbound() {
+            commands.push(IpTablesCommands::RestoreFrom {
+                source_ip,
+                filter: self.filter(),
+                probability: self.probability,
+                action: self.action,
+            });
+        }
+        if self.direction.outbound() {
+            commands.push(IpTablesCommands::RestoreTo {
+                destination_ip: source_ip,
+                filter: self.filter(),
+                probability: self.probability,
+                action: self.action,
+            });
+        }
+        commands
+    }
+}
+
+/// Which partition to heal. Any option left out takes its default, unless all of them are:
+/// then every partition recorded between the peers gets healed.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+pub struct HealOptions {
+    pub probability: Option<f64>,
+    pub direction: Option<Direction>,
+    pub protocol: Option<Protocol>,
+    pub port: Option<PortRange>,
+    pub action: Option<Action>,
+}
+
+impl HealOptions {
+    /// The partition to heal, unless none of its options were given.
+    pub fn partition(&self) -> Option<Partition> {
+        (*self != Self::default()).then(|| Partition {
+            probability: self.probability,
+            direction: self.direction.unwrap_or_default(),
+            protocol: self.protocol,
+            port: self.port,
+            action: self.action.unwrap_or_default(),
+        })
+    }
+}
+
+impl From<Partition> for HealOptions {
+    fn from(partition: Partition) -> Self {
+        Self {
+            probability: partition.probability,
+            direction: Some(partition.direction),
+            protocol: partition.protocol,
+            port: partition.port,
+            action: Some(partition.action),
+        }
+    }
+}
+
+/// What a fault does to the traffic between its source and target peer.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum FaultKind {
+    /// The target peer drops (or rejects) the traffic from/to the source peer.
+    Partition(Partition),
+    /// The target peer impairs the traffic it sends to the source peer.
+    Netem(Netem),
+    /// The target peer caps the rate at which it sends traffic to the source peer.
+    Throttle { rate_kbit: u32 },
+}
+
+/// A fault the supervisor has applied to the traffic between two peers.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Fault {
+    pub id: Uuid,
+    pub source: Uuid,
+    /// The peer whose firewall (or traffic shaping) holds the fault.
+    pub target: Uuid,
+    #[serde(flatten)]
+    pub kind: FaultKind,
+    #[serde(with = "humantime_serde")]
+    pub applied_at: SystemTime,
+    /// When the supervisor heals the fault on its own, if ever.
+    #[serde(default, with = "humantime_serde")]
+    pub expires_at: Option<SystemTime>,
+}
+
+impl Fault {
+    pub fn new(source: Uuid, target: Uuid, kind: FaultKind) -> Self {
+        Self {
+            id: Uuid::new_v4(),
+            source,
+            target,
+            kind,
+            applied_at: SystemTime::now(),
+            expires_at: None,
+        }
+    }
+
+    /// Let the fault expire after the given time, or never.
+    pub fn with_ttl(mut self, ttl: Option<Duration>) -> Self {
+        self.expires_at = ttl.map(|ttl| self.applied_at + ttl);
+        self
+    }
+
+    pub fn is_expired(&self, now: SystemTime) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+
+    fn between(&self, source: Uuid, target: Uuid) -> bool {
+        self.source == source && self.target == target
+    }
+}
+
+/// The direction that cuts the given ways, if any.
+fn direction(inbound: bool, outbound: bool) -> Option<Direction> {
+    match (inbound, outbound) {
+        (true, true) => Some(Direction::Both),
+        (true, false) => Some(Direction::Inbound),
+        (false, true) => Some(Direction::Outbound),
+        (false, false) => None,
+    }
+}
+
+/// The faults the supervisor has applied, in the order they were applied.
+#[derive(Debug, Clone, Default)]
+pub struct FaultRegistry {
+    faults: Vec<Fault>,
+}
+
+impl FaultRegistry {
+    pub fn list(&self) -> &[Fault] {
+        &self.faults
+    }
+
+    /// Record a partition that expires after the `ttl` (if any). If the very same partition
+    /// is already recorded, only its expiry gets updated.
+    pub fn add_partition(
+        &mut self,
+        source: Uuid,
+        target: Uuid,
+        partition: Partition,
+        ttl: Option<Duration>,
+    ) {
+        let fault = Fault::new(source, target, FaultKind::Partition(partition)).with_ttl(ttl);
+        match self
+            .faults
+            .iter_mut()
+            .find(|other| other.between(source, target) && other.kind == fault.kind)
+        {
+            Some(other) => other.expires_at = fault.expires_at,
+            None => self.faults.push(fault),
+        }
+    }
+
+    /// Forget the healed directions of every partition that cuts the same traffic.
+    pub fn heal_partition(&mut self, source: Uuid, target: Uuid, healed: &Partition) {
+        self.faults.retain_mut(|fault| {
+            if !fault.between(source, target) {
+                return true;
+            }
+            let FaultKind::Partition(partition) = &mut fault.kind else {
+                return true;
+            };
+            if !partition.same_traffic(healed) {
+                return true;
+            }
+            let inbound = partition.direction.inbound() && !healed.direction.inbound();
+            let outbound = partition.direction.outbound() && !healed.direction.outbound();
+            match direction(inbound, outbound) {
+                Some(direction) => {
+                    partition.direction = direction;
+                    true
+                }
+                None => false,
+            }
+        });
+    }
+
+    /// The part of the partition fault that no other recorded partition of the same traffic
+    /// between the peers still cuts, i.e. what healing it has to undo. `None` if the other
+    /// partitions cover all of it, or if the fault isn't a partition.
+    pub fn uncovered(&self, fault: &Fault) -> Option<Partition> {
+        let FaultKind::Partition(partition) = fault.kind else {
+            return None;
+        };
+        let (mut inbound, mut outbound) = (
+            partition.direction.inbound(),
+            partition.direction.outbound(),
+        );
+        for other in &self.faults {
+            if other.id == fault.id || !other.between(fault.source, fault.target) {
+                continue;
+            }
+            if let FaultKind::Partition(other) = other.kind {
+                if other.same_traffic(&partition) {
+                    inbound &= !other.direction.inbound();
+                    outbound &= !other.direction.outbound();
+                }
+            }
+        }
+        Some(Partition {
+            direction: direction(inbound, outbound)?,
+            ..partition
+        })
+    }
+
+    /// Record the fault as is.
+    pub fn record(&mut self, fault: Fault) {
+        self.faults.push(fault);
+    }
+
+    /// Forget the fault with the given id.
+    pub fn remove(&mut self, id: Uuid) {
+        self.faults.retain(|fault| fault.id != id);
+    }
+
+    /// Record a traffic shaping fault that expires after the `ttl` (if any), replacing
+    /// the one of the same kind between the peers.
+    pub fn set_shaping(
+        &mut self,
+        source: Uuid,
+        target: Uuid,
+        kind: FaultKind,
+        ttl: Option<Duration>,
+    ) {
+        self.clear_shaping(source, target, &kind);
+        self.faults
+            .push(Fault::new(source, target, kind).with_ttl(ttl));
+    }
+
+    /// The faults that expired by `now`.
+    pub fn expired(&self, now: SystemTime) -> Vec<Fault> {
+        self.faults
+            .iter()
+            .filter(|fault| fault.is_expired(now))
+            .cloned()
+            .collect()
+    }
+
+    /// Forget the traffic shaping fault of the same kind between the peers.
+    pub fn clear_shaping(&mut self, source: Uuid, target: Uuid, kind: &FaultKind) {
+        self.faults.retain(|fault| {
+            !fault.between(source, target)
+                || std::mem::discriminant(&fault.kind) != std::mem::discriminant(kind)
+        });
+    }
+
+    /// Replace all the recorded partitions with the given ones.
+    pub fn replace_partitions(&mut self, partitions: Vec<Fault>) {
+        self.faults
+            .retain(|fault| !matches!(fault.kind, FaultKind::Partition(_)));
+        self.faults.extend(partitions);
+    }
+
+    pub fn partitions(&self) -> Vec<Fault> {
+        self.faults
+            .iter()
+            .filter(|fault| matches!(fault.kind, FaultKind::Partition(_)))
+            .cloned()
+            .collect()
+    }
+
+    pub fn clear(&mut self) {
+        self.faults.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_partition_directions() {
+        let ip_addr = "192.168.192.4".parse().unwrap();
+        let partition = |direction| Partition {
+            direction,
+            protocol: Some(Protocol::Tcp),
+            port: Some("9000:9010".parse().unwrap()),
+            ..Default::default()
+        };
+        let specs = |commands: Vec<IpTablesCommands>| {
+            commands
+                .iter()
+                .map(|command| command.rule_spec().unwrap())
+                .collect::<Vec<_>>()
+        };
+        let inbound = "-s 192.168.192.4 -p tcp --dport 9000:9010 -j DROP";
+        let outbound = "-d 192.168.192.4 -p tcp --dport 9000:9010 -j DROP";
+        for (direction, expected) in [
+            (Direction::Inbound, vec![inbound]),
+            (Direction::Outbound, vec![outbound]),
+            (Direction::Both, vec![inbound, outbound]),
+        ] {
+            let partition = partition(direction);
+            partition.validate().unwrap();
+            assert_eq!(specs(partition.drop_commands(ip_addr)), expected);
+            assert_eq!(specs(partition.restore_commands(ip_addr)), expected);
+        }
+
+        let commands = partition(Direction::Outbound).drop_commands(ip_addr);
+        assert!(matches!(
+            commands[..],
+            [IpTablesCommands::DropTo { destination_ip, .. }] if destination_ip == ip_addr
+        ));
+        // Our chain also has to see the packets the target peer sends.
+        assert!(commands[0]
+            .script()
+            .contains("/usr/sbin/iptables -I OUTPUT -j PARTITION_SIM"));
+        assert!(matches!(
+            partition(Direction::Outbound).restore_commands(ip_addr)[..],
+            [IpTablesCommands::RestoreTo { .. }]
+        ));
+    }
+
+    #[test]
+    fn test_probabilistic_partition() {
+        let ip_addr = "192.168.192.4".parse().unwrap();
+        let partition = Partition {
+            probability: Some(0.2),
+            ..Default::default()
+        };
+        partition.validate().unwrap();
+        let specs = |commands: Vec<IpTablesCommands>| {
+            commands
+                .iter()
+                .map(|command| command.rule_spec().unwrap())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            specs(partition.drop_commands(ip_addr)),
+            ["-s 192.168.192.4 -m statistic --mode random --probability 0.2 -j DROP"]
+        );
+        // Healing has to delete the very same rule.
+        assert_eq!(
+            specs(partition.restore_commands(ip_addr)),
+            specs(partition.drop_commands(ip_addr))
+        );
+
+        for probability in [0.0, -0.1, 1.5, f64::NAN] {
+            assert!(Partition {
+                probability: Some(probability),
+                ..Default::default()
+            }
+            .validate()
+            .is_err());
+        }
+        assert!(Partition {
+            probability: Some(1.0),
+            ..Default::default()
+        }
+        .validate()
+        .is_ok());
+    }
+
+    #[test]
+    fn test_add_and_heal_partition() {
+        let (source, target) = (Uuid::new_v4(), Uuid::new_v4());
+        let mut registry = FaultRegistry::default();
+        let both = Partition {
+            direction: Direction::Both,
+            ..Default::default()
+        };
+        registry.add_partition(source, target, both, None);
+        registry.add_partition(source, target, both, None);
+        assert_eq!(registry.list().len(), 1);
+
+        // Healing a single direction keeps the other one around.
+        registry.heal_partition(source, target, &Partition::default());
+        assert_eq!(
+            registry.list()[0].kind,
+            FaultKind::Partition(Partition {
+                direction: Direction::Outbound,
+                ..Default::default()
+            })
+        );
+
+        // A partition of other traffic is left alone.
+        registry.heal_partition(
+            source,
+            target,
+            &Partition {
+                direction: Direction::Both,
+                probability: Some(0.5),
+                ..Default::default()
+            },
+        );
+        assert_eq!(registry.list().len(), 1);
+
+        registry.heal_partition(source, target, &both);
+        assert!(registry.list().is_empty());
+    }
+
+    #[test]
+    fn test_expiry() {
+        let (source, target) = (Uuid::new_v4(), Uuid::new_v4());
+        let mut registry = FaultRegistry::default();
+        let ttl = Some(Duration::from_secs(30));
+        registry.add_partition(source, target, Partition::default(), ttl);
+        registry.set_shaping(source, target, FaultKind::Throttle { rate_kbit: 512 }, None);
+
+        let later = SystemTime::now() + Duration::from_secs(60);
+        assert_eq!(registry.expired(SystemTime::now()), vec![]);
+        assert_eq!(registry.expired(later).len(), 1);
+
+        // Partitioning again without a ttl keeps the partition around for good.
+        registry.add_partition(source, target, Partition::default(), None);
+        assert_eq!(registry.list().len(), 2);
+        assert_eq!(registry.expired(later), vec![]);
+    }
+
+    #[test]
+    fn test_uncovered_by_overlapping_partitions() {
+        let (source, target) = (Uuid::new_v4(), Uuid::new_v4());
+        let mut registry = FaultRegistry::default();
+        let partition = |direction| Partition {
+            direction,
+            ..Default::default()
+        };
+        registry.add_partition(source, target, partition(Direction::Both), None);
+        registry.add_partition(
+            source,
+            target,
+            partition(Direction::Inbound),
+            Some(Duration::ZERO),
+        );
+        let expiring = registry.expired(SystemTime::now()).remove(0);
+        // The permanent partition still needs the inbound rule.
+        assert_eq!(registry.uncovered(&expiring), None);
+        registry.remove(expiring.id);
+        assert_eq!(registry.list().len(), 1);
+        assert_eq!(
+            registry.list()[0].kind,
+            FaultKind::Partition(partition(Direction::Both))
+        );
+
+        // Only the direction nothing else cuts gets healed.
+        let mut registry = FaultRegistry::default();
+        registry.add_partition(source, target, partition(Direction::Inbound), None);
+        registry.add_partition(
+            source,
+            target,
+            partition(Direction::Both),
+            Some(Duration::ZERO),
+        );
+        let expiring = registry.expired(SystemTime::now()).remove(0);
+        assert_eq!(
+            registry.uncovered(&expiring),
+            Some(partition(Direction::Outbound))
+        );
+        // Partitions of other traffic don't count.
+        registry.add_partition(
+            source,
+            target,
+            Partition {
+                probability: Some(0.5),
+                ..partition(Direction::Both)
+            },
+            None,
+        );
+        assert_eq!(
+            registry.uncovered(&expiring),
+            Some(partition(Direction::Outbound))
+        );
+    }
+
+    #[test]
+    fn test_set_and_clear_shaping() {
+        let (source, target) = (Uuid::new_v4(), Uuid::new_v4());
+        let mut registry = FaultRegistry::default();
+        registry.set_shaping(source, target, FaultKind::Throttle { rate_kbit: 512 }, None);
+        registry.set_shaping(
+            source,
+            target,
+            FaultKind::Throttle { rate_kbit: 1024 },
+            None,
+        );
+        registry.set_shaping(
+            source,
+            target,
+            FaultKind::Netem(Netem {
+                delay_ms: 100,
+                ..Default::default()
+            }),
+            None,
+        );
+        assert_eq!(registry.list().len(), 2);
+        assert_eq!(
+            registry.list()[0].kind,
+            FaultKind::Throttle { rate_kbit: 1024 }
+        );
+
+        registry.clear_shaping(source, target, &FaultKind::Throttle { rate_kbit: 0 });
+        assert_eq!(registry.list().len(), 1);
+        assert!(matches!(registry.list()[0].kind, FaultKind::Netem(_)));
+    }
+
+    #[test]
+    fn test_serialize_fault() {
+        let fault = Fault::new(
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+            FaultKind::Throttle { rate_kbit: 512 },
+        );
+        let json = serde_json::to_value(&fault).unwrap();
+        assert_eq!(json["kind"], "throttle");
+        assert_eq!(json["rate_kbit"], 512);
+        assert_eq!(serde_json::from_value::<Fault>(json).unwrap(), fault);
+    }
+}
@@ -1,7 +1,14 @@
 pub mod commands;
+pub mod drift;
 pub mod errors;
+pub mod events;
+pub mod faults;
+pub mod nemesis;
 mod peer;
+pub mod rules;
+pub mod scenario;
 mod supervisor;
+pub mod topology;
 
 pub type Result<T> = std::result::Result<T, errors::PartitionSimError>;
 pub type Error = errors::PartitionSimError;
@@ -0,0 +1,319 @@
+use std::time::Duration;
+
+use rand::distributions::{Distribution, WeightedIndex};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+
+use crate::commands::Direction;
+use crate::faults::Partition;
+use crate::scenario::{PeerRef, Scenario, Step, StepAction};
+use crate::topology::Shape;
+
+/// How likely the nemesis is to pick each kind of fault, relative to the others.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Weights {
+    /// Cut the link between two random peers, in a random direction.
+    pub partition: u32,
+    /// Cut a random peer off from all the others.
+    pub isolate: u32,
+    /// Delay the traffic between two random peers.
+    pub delay: u32,
+    /// Split the cluster into a random majority and minority.
+    pub shape: u32,
+    /// Heal one of the faults the nemesis applied.
+    pub heal: u32,
+    /// Heal all of the faults.
+    pub restore: u32,
+}
+
+impl Default for Weights {
+    fn default() -> Self {
+        Self {
+            partition: 3,
+            isolate: 2,
+            delay: 2,
+            shape: 1,
+            heal: 4,
+            restore: 1,
+        }
+    }
+}
+
+/// How the nemesis behaves. The same config and seed always lead to the same faults.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct NemesisConfig {
+    /// Makes the run reproducible. A random one is picked (and logged) if not given.
+    pub seed: Option<u64>,
+    /// The shortest time to wait before the next fault.
+    #[serde(with = "humantime_serde")]
+    pub min_interval: Duration,
+    /// The longest time to wait before the next fault.
+    #[serde(with = "humantime_serde")]
+    pub max_interval: Duration,
+    /// The longest delay (in milliseconds) a `delay` fault adds.
+    pub max_delay_ms: u32,
+    pub weights: Weights,
+}
+
+impl Default for NemesisConfig {
+    fn default() -> Self {
+        Self {
+            seed: None,
+            min_interval: Duration::from_secs(5),
+            max_interval: Duration::from_secs(30),
+            max_delay_ms: 500,
+            weights: Weights::default(),
+        }
+    }
+}
+
+/// Keeps choosing random faults to apply to the cluster, Jepsen style. It only picks the
+/// faults; they get applied like the steps of a scenario. Peers are referred to by their
+/// index, with the peers ordered by address, so that the same seed replays the same faults
+/// after the cluster is reloaded (as long as the peers keep their addresses).
+#[derive(Debug, Clone)]
+pub struct Nemesis {
+    config: NemesisConfig,
+    seed: u64,
+    rng: StdRng,
+    weights: WeightedIndex<u32>,
+    /// The faults the nemesis applied and didn't heal yet.
+    active: Vec<StepAction>,
+    steps: usize,
+}
+
+impl Nemesis {
+    pub fn new(config: NemesisConfig) -> crate::Result<Self> {
+        if config.min_interval > config.max_interval {
+            return Err(crate::Error::InvalidParameter(
+                "min_interval must not exceed max_interval".into(),
+            ));
+        }
+        let weights = &config.weights;
+        let weights = WeightedIndex::new([
+            weights.partition,
+            weights.isolate,
+            weights.delay,
+            weights.shape,
+            weights.heal,
+            weights.restore,
+        ])
+        .map_err(|_| {
+            crate::Error::InvalidParameter("at least one weight must be positive".into())
+        })?;
+        let seed = config.seed.unwrap_or_else(rand::random);
+        Ok(Self {
+            config,
+            seed,
+            rng: StdRng::seed_from_u64(seed),
+            weights,
+            active: vec![],
+            steps: 0,
+        })
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// Two different random peers.
+    fn pick_pair(&mut self, peer_count: usize) -> (PeerRef, PeerRef) {
+        let source = self.rng.gen_range(0..peer_count);
+        let target = (source + self.rng.gen_range(1..peer_count)) % peer_count;
+        (PeerRef::Index(source), PeerRef::Index(target))
+    }
+
+    /// The action that undoes the given one.
+    fn undo(action: StepAction) -> StepAction {
+        match action {
+            StepAction::Partition {
+                source,
+                target,
+                partition,
+            } => StepAction::Heal {
+                source,
+                target,
+                partition: partition.into(),
+            },
+            StepAction::Isolate { peer, action } => StepAction::Rejoin {
+                peer,
+                action: Some(action),
+            },
+            StepAction::Delay { source, target, .. } => StepAction::ClearNetem { source, target },
+            _ => StepAction::Restore {},
+        }
+    }
+
+    /// Pick how long to wait before the next fault, and what it is.
+    pub fn next_step(&mut self, peer_count: usize) -> crate::Result<(Duration, StepAction)> {
+        if peer_count < 2 {
+            return Err(crate::Error::InvalidParameter(
+                "the nemesis needs at least two peers".into(),
+            ));
+        }
+        let wait = self
+            .rng
+            .gen_range(self.config.min_interval..=self.config.max_interval);
+        let action = match self.weights.sample(&mut self.rng) {
+            0 => {
+                let (source, target) = self.pick_pair(peer_count);
+                let direction = [Direction::Inbound, Direction::Outbound, Direction::Both]
+                    [self.rng.gen_range(0..3)];
+                StepAction::Partition {
+                    source,
+                    target,
+                    partition: Partition {
+                        direction,
+                        ..Default::default()
+                    },
+                }
+            }
+            1 => StepAction::Isolate {
+                peer: PeerRef::Index(self.rng.gen_range(0..peer_count)),
+                action: Default::default(),
+            },
+            2 => {
+                let (source, target) = self.pick_pair(peer_count);
+                StepAction::Delay {
+                    source,
+                    target,
+                    delay_ms: self.rng.gen_range(1..=self.config.max_delay_ms.max(1)),
+                    jitter_ms: 0,
+                }
+            }
+            3 => StepAction::Shape {
+                shape: Shape::MajorityMinority { minority: None },
+                seed: Some(self.rng.gen()),
+                action: Default::default(),
+            },
+            4 if !self.active.is_empty() => {
+                let index = self.rng.gen_range(0..self.active.len());
+                Self::undo(self.active.remove(index))
+            }
+            _ => StepAction::Restore {},
+        };
+        match &action {
+            StepAction::Restore {} => self.active.clear(),
+            // Healing a shape restores everything, since its cuts aren't known here.
+            StepAction::Partition { .. }
+            | StepAction::Isolate { .. }
+            | StepAction::Delay { .. }
+            | StepAction::Shape { .. } => self.active.push(action.clone()),
+            _ => {}
+        }
+        self.steps += 1;
+        tracing::info!(
+            "Nemesis (seed {}) step {}: in {:?}, {:?}",
+            self.seed,
+            self.steps,
+            wait,
+            action
+        );
+        Ok((wait, action))
+    }
+
+    /// The first `steps` faults the nemesis would pick, as a scenario that replays them.
+    pub fn plan(&mut self, peer_count: usize, steps: usize) -> crate::Result<Scenario> {
+        let mut at = Duration::ZERO;
+        let steps = (0..steps)
+            .map(|_| {
+                let (wait, action) = self.next_step(peer_count)?;
+                at += wait;
+                Ok(Step { at, action })
+            })
+            .collect::<crate::Result<_>>()?;
+        Ok(Scenario {
+            name: format!("nemesis (seed {})", self.seed),
+            steps,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_same_seed_same_plan() {
+        let config = NemesisConfig {
+            seed: Some(42),
+            ..Default::default()
+        };
+        let plan = Nemesis::new(config.clone()).unwrap().plan(5, 50).unwrap();
+        assert_eq!(plan, Nemesis::new(config).unwrap().plan(5, 50).unwrap());
+
+        let other_plan = Nemesis::new(NemesisConfig {
+            seed: Some(43),
+            ..Default::default()
+        })
+        .unwrap()
+        .plan(5, 50)
+        .unwrap();
+        assert_ne!(plan, other_plan);
+
+        for step in &plan.steps {
+            if let StepAction::Partition { source, target, .. }
+            | StepAction::Delay { source, target, .. } = &step.action
+            {
+                assert_ne!(source, target);
+            }
+        }
+    }
+
+    #[test]
+    fn test_heal_undoes_an_active_fault() {
+        let mut nemesis = Nemesis::new(NemesisConfig {
+            seed: Some(1),
+            weights: Weights {
+                partition: 1,
+                isolate: 0,
+                delay: 0,
+                shape: 0,
+                heal: 0,
+                restore: 0,
+            },
+            ..Default::default()
+        })
+        .unwrap();
+        let (_, partition) = nemesis.next_step(3).unwrap();
+        nemesis.weights = WeightedIndex::new([0, 0, 0, 0, 1, 0]).unwrap();
+        let (_, heal) = nemesis.next_step(3).unwrap();
+        assert_eq!(heal, Nemesis::undo(partition));
+        // Nothing left to heal.
+        assert_eq!(nemesis.next_step(3).unwrap().1, StepAction::Restore {});
+    }
+
+    #[test]
+    fn test_invalid_config() {
+        assert!(Nemesis::new(NemesisConfig {
+            weights: Weights {
+                partition: 0,
+                isolate: 0,
+                delay: 0,
+                shape: 0,
+                heal: 0,
+                restore: 0,
+            },
+            ..Default::default()
+        })
+        .is_err());
+        assert!(Nemesis::new(NemesisConfig {
+            min_interval: Duration::from_secs(10),
+            max_interval: Duration::from_secs(1),
+            ..Default::default()
+        })
+        .is_err());
+    }
+
+    #[test]
+    fn test_too_few_peers() {
+        let mut nemesis = Nemesis::new(NemesisConfig::default()).unwrap();
+        assert!(nemesis.next_step(0).is_err());
+        assert!(nemesis.next_step(1).is_err());
+        assert!(nemesis.plan(1, 3).is_err());
+        assert!(nemesis.next_step(2).is_ok());
+    }
+}
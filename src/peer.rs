@@ -1,15 +1,48 @@
 use openssh::{Session, SessionBuilder};
+use serde::{Deserialize, Serialize};
 use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::Arc;
 use uuid::Uuid;
 
-#[derive(Debug)]
+use crate::commands::Commands;
+
+/// The firewall a peer manages its packet filtering rules with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Firewall {
+    /// The (legacy or nft-backed) `iptables` binary.
+    IpTables,
+    /// The `nft` binary, for peers that don't ship `iptables` at all.
+    NfTables,
+}
+
+impl FromStr for Firewall {
+    type Err = crate::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "iptables" => Ok(Self::IpTables),
+            "nftables" | "nft" => Ok(Self::NfTables),
+            _ => Err(crate::Error::InvalidParameter(format!(
+                "unknown firewall: {}. Expected one of: iptables, nftables",
+                s
+            ))),
+        }
+    }
+}
+
+/// A peer and how to reach it. Clones share the SSH session.
+#[derive(Debug, Clone)]
 pub struct Peer {
     pub id: Uuid,
     pub ip_addr: IpAddr,
     // pub hostname: String,
-    pub session: Option<Session>,
+    pub session: Option<Arc<Session>>,
     pub user: String,
     pub keyfile: Option<String>,
+    /// The firewall to manage the rules with. Detected on the first connect if not set.
+    pub firewall: Option<Firewall>,
 }
 
 impl Peer {
@@ -21,6 +54,46 @@ impl Peer {
             session: None,
             user: user.unwrap_or("root").to_string(),
             keyfile: keyfile.map(|s| s.to_string()),
+            firewall: None,
+        }
+    }
+
+    pub fn with_firewall(mut self, firewall: Option<Firewall>) -> Self {
+        self.firewall = firewall;
+        self
+    }
+
+    /// Translate the firewall commands into the ones for the firewall the peer actually has.
+    pub fn translate(&self, command: Commands) -> Commands {
+        match (command, self.firewall) {
+            (Commands::IpTables(command), Some(Firewall::NfTables)) => {
+                Commands::NfTables(command.into())
+            }
+            (command, _) => command,
+        }
+    }
+
+    /// Prefer `iptables` if the peer has it and fall back to `nft` otherwise.
+    async fn detect_firewall(session: &Session) -> crate::Result<Firewall> {
+        let has_iptables = session
+            .raw_command("test -x /usr/sbin/iptables")
+            .status()
+            .await?
+            .success();
+        if has_iptables {
+            return Ok(Firewall::IpTables);
+        }
+        let has_nft = session
+            .raw_command("test -x /usr/sbin/nft")
+            .status()
+            .await?
+            .success();
+        if has_nft {
+            Ok(Firewall::NfTables)
+        } else {
+            Err(crate::Error::Other(
+                "neither /usr/sbin/iptables nor /usr/sbin/nft found on the peer".into(),
+            ))
         }
     }
 
@@ -38,7 +111,17 @@ impl Peer {
             session_builder.keyfile(std::path::PathBuf::from(keyfile.to_owned()));
         }
         let session = session_builder.connect(self.ip_addr.to_string()).await?;
-        self.session = Some(session);
+        if self.firewall.is_none() {
+            let firewall = Self::detect_firewall(&session).await?;
+            tracing::debug!(
+                "Detected {:?} on {} (ip: {}).",
+                firewall,
+                self.id,
+                self.ip_addr
+            );
+            self.firewall = Some(firewall);
+        }
+        self.session = Some(Arc::new(session));
 
         Ok(())
     }
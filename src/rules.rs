@@ -0,0 +1,164 @@
+use std::net::IpAddr;
+
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::commands::{spec_from_comment, PortRange};
+
+/// A rule in a peer's firewall, as parsed from the output of `IpTablesCommands::Get`.
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub struct Rule {
+    /// What happens to the matching packets, like `DROP` or `REJECT`.
+    pub target: String,
+    /// The protocol of the matching packets, like `tcp`, or `None` for all of them.
+    pub protocol: Option<String>,
+    /// The source address of the matching packets, or `None` for any.
+    pub source: Option<IpAddr>,
+    /// The destination address of the matching packets, or `None` for any.
+    pub destination: Option<IpAddr>,
+    /// The destination port(s) of the matching packets, or `None` for any.
+    pub ports: Option<PortRange>,
+    /// The probability with which a matching packet is affected, or `None` if it always is.
+    pub probability: Option<f64>,
+    /// The peer the `source` belongs to, if any.
+    pub source_peer: Option<Uuid>,
+    /// The peer the `destination` belongs to, if any.
+    pub destination_peer: Option<Uuid>,
+}
+
+/// Parse an address as printed by `iptables -S`, which always comes with a prefix length.
+fn parse_addr(addr: &str) -> crate::Result<IpAddr> {
+    let addr = addr.split('/').next().unwrap_or(addr);
+    addr.parse()
+        .map_err(|_| crate::Error::Other(format!("couldn't parse address: {}", addr)))
+}
+
+impl Rule {
+    /// Parse a rule specification in the `iptables -S` format (without the leading `-A <chain>`).
+    pub fn from_spec(spec: &str) -> crate::Result<Self> {
+        let mut rule = Rule::default();
+        let mut tokens = spec.split_whitespace();
+        let value = |flag: &str, tokens: &mut std::str::SplitWhitespace| {
+            tokens.next().map(|value| value.to_string()).ok_or_else(|| {
+                crate::Error::Other(format!("missing value for {} in: {}", flag, spec))
+            })
+        };
+        while let Some(token) = tokens.next() {
+            match token {
+                "-s" => rule.source = Some(parse_addr(&value(token, &mut tokens)?)?),
+                "-d" => rule.destination = Some(parse_addr(&value(token, &mut tokens)?)?),
+                "-p" => rule.protocol = Some(value(token, &mut tokens)?),
+                "--dport" | "--dports" => rule.ports = Some(value(token, &mut tokens)?.parse()?),
+                "--probability" => {
+                    let probability = value(token, &mut tokens)?;
+                    rule.probability = Some(probability.parse().map_err(|_| {
+                        crate::Error::Other(format!("couldn't parse probability: {}", probability))
+                    })?);
+                }
+                "-j" => rule.target = value(token, &mut tokens)?,
+                _ => {}
+            }
+        }
+        Ok(rule)
+    }
+
+    /// Fill in the peers the addresses belong to, as far as `find_peer` knows.
+    pub fn resolve_peers(&mut self, find_peer: impl Fn(IpAddr) -> Option<Uuid>) {
+        self.source_peer = self.source.and_then(&find_peer);
+        self.destination_peer = self.destination.and_then(&find_peer);
+    }
+
+    /// Whether both rules match the same packets and do the same with them.
+    /// The probabilities only have to be close since `iptables` rounds them.
+    pub fn same_as(&self, other: &Rule) -> bool {
+        let same_probability = match (self.probability, other.probability) {
+            (Some(a), Some(b)) => (a - b).abs() < 1e-6,
+            (None, None) => true,
+            _ => false,
+        };
+        self.target == other.target
+            && self.protocol == other.protocol
+            && self.source == other.source
+            && self.destination == other.destination
+            && self.ports == other.ports
+            && same_probability
+    }
+}
+
+/// Parse all the rules out of the output of `IpTablesCommands::Get`. That is the
+/// `iptables -S` listing of our chain for `iptables` peers, and the listing of our
+/// table for `nft` peers, where each rule carries its parameters (or, for older
+/// rules, its `iptables` specification) as a comment.
+pub fn parse_rules(output: &str) -> crate::Result<Vec<Rule>> {
+    output
+        .lines()
+        .map(|line| line.trim())
+        .filter_map(|line| {
+            if let Some(spec) = line.strip_prefix("-A ") {
+                // Skip the chain name.
+                spec.split_once(' ').map(|(_, spec)| spec.to_string())
+            } else {
+                let (_, comment) = line.split_once("comment \"")?;
+                let (comment, _) = comment.split_once('"')?;
+                Some(spec_from_comment(comment).unwrap_or_else(|| comment.to_string()))
+            }
+        })
+        .map(|spec| Rule::from_spec(&spec))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_iptables_rules() {
+        let output = "\
+-N PARTITION_SIM
+-A PARTITION_SIM -s 192.168.192.4/32 -j DROP
+-A PARTITION_SIM -d 192.168.192.5/32 -p tcp -m tcp --dport 9000:9010 -m statistic --mode random --probability 0.20000000019 -j REJECT --reject-with tcp-reset
+";
+        let rules = parse_rules(output).unwrap();
+        assert_eq!(rules.len(), 2);
+        assert_eq!(rules[0].target, "DROP");
+        assert_eq!(rules[0].source, Some("192.168.192.4".parse().unwrap()));
+        assert_eq!(rules[0].destination, None);
+        assert_eq!(rules[1].target, "REJECT");
+        assert_eq!(rules[1].protocol.as_deref(), Some("tcp"));
+        assert_eq!(rules[1].destination, Some("192.168.192.5".parse().unwrap()));
+        assert_eq!(rules[1].ports, Some("9000:9010".parse().unwrap()));
+        assert!(rules[1].same_as(
+            &Rule::from_spec("-d 192.168.192.5 -p tcp --dport 9000:9010 -m statistic --mode random --probability 0.2 -j REJECT --reject-with tcp-reset").unwrap()
+        ));
+    }
+
+    #[test]
+    fn test_parse_nft_rules() {
+        let output = r#"table inet partition_sim {
+	chain input {
+		type filter hook input priority -10; policy accept;
+		ip6 saddr fd00::4 drop comment "-s fd00::4 -j DROP"
+	}
+	chain output {
+		type filter hook output priority -10; policy accept;
+		ip daddr 192.168.192.5 tcp dport 9000-9010 reject with tcp reset comment "-d 192.168.192.5 tcp 9000:9010 - reject"
+	}
+}
+"#;
+        let rules = parse_rules(output).unwrap();
+        assert_eq!(
+            rules,
+            vec![
+                Rule {
+                    target: "DROP".into(),
+                    source: Some("fd00::4".parse().unwrap()),
+                    ..Default::default()
+                },
+                Rule::from_spec(
+                    "-d 192.168.192.5 -p tcp --dport 9000:9010 -j REJECT --reject-with tcp-reset"
+                )
+                .unwrap(),
+            ]
+        );
+    }
+}
@@ -0,0 +1,402 @@
+use std::net::IpAddr;
+use std::path::Path;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::commands::{Action, Netem};
+use crate::faults::{HealOptions, Partition};
+use crate::topology::Shape;
+use crate::Supervisor;
+
+/// A timeline of faults to apply to the cluster, usually loaded from a YAML or TOML file:
+///
+/// ```yaml
+/// name: isolate the first peer
+/// steps:
+///   - at: 0s
+///     isolate: { peer: 0 }
+///   - at: 10s
+///     rejoin: { peer: 0 }
+///   - at: 15s
+///     delay: { source: 0, target: 1, delay_ms: 200 }
+///   - at: 30s
+///     restore: {}
+/// ```
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Scenario {
+    #[serde(default)]
+    pub name: String,
+    pub steps: Vec<Step>,
+}
+
+/// Something to do to the cluster, some time after the scenario started.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Step {
+    #[serde(with = "humantime_serde")]
+    pub at: Duration,
+    #[serde(flatten)]
+    pub action: StepAction,
+}
+
+/// A peer, given by its index (with the peers ordered by address), its id or its address.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum PeerRef {
+    Index(usize),
+    Name(String),
+}
+
+impl PeerRef {
+    pub fn resolve(&self, supervisor: &Supervisor) -> crate::Result<Uuid> {
+        let peer_id = match self {
+            Self::Index(index) => supervisor.get_peer_ids().get(*index).copied(),
+            Self::Name(name) => match (name.parse::<Uuid>(), name.parse::<IpAddr>()) {
+                (Ok(peer_id), _) => Some(supervisor.get_peer(peer_id)?.id),
+                (_, Ok(ip_addr)) => supervisor.find_peer_by_ip(ip_addr),
+                _ => None,
+            },
+        };
+        peer_id.ok_or_else(|| crate::Error::InvalidParameter(format!("unknown peer: {:?}", self)))
+    }
+}
+
+/// What a step does. Each one maps onto the supervisor method (and API endpoint) of the same name.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StepAction {
+    Partition {
+        source: PeerRef,
+        target: PeerRef,
+        #[serde(flatten)]
+        partition: Partition,
+    },
+    Heal {
+        source: PeerRef,
+        target: PeerRef,
+        #[serde(flatten)]
+        partition: HealOptions,
+    },
+    Isolate {
+        peer: PeerRef,
+        #[serde(default)]
+        action: Action,
+    },
+    Rejoin {
+        peer: PeerRef,
+        action: Option<Action>,
+    },
+    Groups {
+        groups: Vec<Vec<PeerRef>>,
+        #[serde(default)]
+        action: Action,
+    },
+    HealGroups {
+        groups: Vec<Vec<PeerRef>>,
+        #[serde(default)]
+        action: Action,
+    },
+    Shape {
+        #[serde(flatten)]
+        shape: Shape,
+        seed: Option<u64>,
+        #[serde(default)]
+        action: Action,
+    },
+    Delay {
+        source: PeerRef,
+        target: PeerRef,
+        delay_ms: u32,
+        #[serde(default)]
+        jitter_ms: u32,
+    },
+    Netem {
+        source: PeerRef,
+        target: PeerRef,
+        #[serde(flatten)]
+        netem: Netem,
+    },
+    ClearNetem {
+        source: PeerRef,
+        target: PeerRef,
+    },
+    Throttle {
+        source: PeerRef,
+        target: PeerRef,
+        rate_kbit: u32,
+    },
+    ClearThrottle {
+        source: PeerRef,
+        target: PeerRef,
+    },
+    Restore {},
+}
+
+impl Scenario {
+    pub fn from_yaml(text: &str) -> crate::Result<Self> {
+        serde_yaml::from_str::<Self>(text)
+            .map_err(|err| crate::Error::InvalidParameter(format!("invalid scenario: {}", err)))?
+            .validated()
+    }
+
+    pub fn from_toml(text: &str) -> crate::Result<Self> {
+        toml::from_str::<Self>(text)
+            .map_err(|err| crate::Error::InvalidParameter(format!("invalid scenario: {}", err)))?
+            .validated()
+    }
+
+    /// Load a scenario from a `.toml` file, or from a YAML (or JSON) one otherwise.
+    pub fn from_path(path: impl AsRef<Path>) -> crate::Result<Self> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path)?;
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("toml") => Self::from_toml(&text),
+            _ => Self::from_yaml(&text),
+        }
+    }
+
+    fn validated(self) -> crate::Result<Self> {
+        if self.steps.windows(2).any(|steps| steps[0].at > steps[1].at) {
+            return Err(crate::Error::InvalidParameter(
+                "the steps of a scenario must be in chronological order".into(),
+            ));
+        }
+        Ok(self)
+    }
+}
+
+impl Supervisor {
+    /// Do what the step says, right away.
+    pub async fn apply_step(&mut self, action: &StepAction) -> crate::Result<()> {
+        let resolve_groups = |supervisor: &Supervisor, groups: &[Vec<PeerRef>]| {
+            groups
+                .iter()
+                .map(|group| {
+                    group
+                        .iter()
+                        .map(|peer| peer.resolve(supervisor))
+                        .collect::<crate::Result<Vec<_>>>()
+                })
+                .collect::<crate::Result<Vec<_>>>()
+        };
+        let isolation = |action: Action| Partition {
+            action,
+            ..Default::default()
+        };
+        match action {
+            StepAction::Partition {
+                source,
+                target,
+                partition,
+            } => {
+                let (source, target) = (source.resolve(self)?, target.resolve(self)?);
+                self.partition(source, target, *partition, None).await?;
+            }
+            StepAction::Heal {
+                source,
+                target,
+                partition,
+            } => {
+                let (source, target) = (source.resolve(self)?, target.resolve(self)?);
+                self.heal(source, target, partition.partition()).await?;
+            }
+            StepAction::Isolate { peer, action } => {
+                let peer = peer.resolve(self)?;
+                self.isolate(peer, isolation(*action), None).await?;
+            }
+            StepAction::Rejoin { peer, action } => {
+                let peer = peer.resolve(self)?;
+                self.rejoin(peer, action.map(isolation)).await?;
+            }
+            StepAction::Groups { groups, action } => {
+                let groups = resolve_groups(self, groups)?;
+                self.partition_groups(&groups, isolation(*action), None)
+                    .await?;
+            }
+            StepAction::HealGroups { groups, action } => {
+                let groups = resolve_groups(self, groups)?;
+                self.heal_groups(&groups, isolation(*action)).await?;
+            }
+            StepAction::Shape {
+                shape,
+                seed,
+                action,
+            } => {
+                self.apply_shape(shape, isolation(*action), *seed, None)
+                    .await?;
+            }
+            StepAction::Delay {
+                source,
+                target,
+                delay_ms,
+                jitter_ms,
+            } => {
+                let (source, target) = (source.resolve(self)?, target.resolve(self)?);
+                let netem = Netem {
+                    delay_ms: *delay_ms,
+                    jitter_ms: *jitter_ms,
+                    ..Default::default()
+                };
+                self.netem(source, target, netem, None).await?;
+            }
+            StepAction::Netem {
+                source,
+                target,
+                netem,
+            } => {
+                let (source, target) = (source.resolve(self)?, target.resolve(self)?);
+                self.netem(source, target, netem.clone(), None).await?;
+            }
+            StepAction::ClearNetem { source, target } => {
+                let (source, target) = (source.resolve(self)?, target.resolve(self)?);
+                self.clear_netem(source, target).await?;
+            }
+            StepAction::Throttle {
+                source,
+                target,
+                rate_kbit,
+            } => {
+                let (source, target) = (source.resolve(self)?, target.resolve(self)?);
+                self.throttle(source, target, *rate_kbit, None).await?;
+            }
+            StepAction::ClearThrottle { source, target } => {
+                let (source, target) = (source.resolve(self)?, target.resolve(self)?);
+                self.clear_throttle(source, target).await?;
+            }
+            StepAction::Restore {} => self.restore().await?,
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::Direction;
+    use crate::Peer;
+
+    #[test]
+    fn test_from_yaml() {
+        let scenario = Scenario::from_yaml(
+            "
+name: isolate the first peer
+steps:
+  - at: 0s
+    isolate: { peer: 0 }
+  - at: 10s
+    partition: { source: 1, target: 192.168.192.3, direction: both, probability: 0.5 }
+  - at: 15s
+    delay: { source: 0, target: 1, delay_ms: 200 }
+  - at: 30s
+    restore: {}
+",
+        )
+        .unwrap();
+        assert_eq!(scenario.steps.len(), 4);
+        assert_eq!(scenario.steps[1].at, Duration::from_secs(10));
+        assert_eq!(
+            scenario.steps[1].action,
+            StepAction::Partition {
+                source: PeerRef::Index(1),
+                target: PeerRef::Name("192.168.192.3".into()),
+                partition: Partition {
+                    direction: Direction::Both,
+                    probability: Some(0.5),
+                    ..Default::default()
+                },
+            }
+        );
+        assert_eq!(scenario.steps[3].action, StepAction::Restore {});
+    }
+
+    #[test]
+    fn test_heal_options() {
+        let scenario = Scenario::from_yaml(
+            "
+steps:
+  - at: 0s
+    heal: { source: 0, target: 1 }
+  - at: 1s
+    heal: { source: 0, target: 1, action: reject }
+",
+        )
+        .unwrap();
+        let options = |action: &StepAction| match action {
+            StepAction::Heal { partition, .. } => *partition,
+            _ => panic!("not a heal: {:?}", action),
+        };
+        // A bare heal heals whatever is recorded between the peers.
+        assert_eq!(options(&scenario.steps[0].action).partition(), None);
+        assert_eq!(
+            options(&scenario.steps[1].action).partition(),
+            Some(Partition {
+                action: Action::Reject,
+                ..Default::default()
+            })
+        );
+    }
+
+    #[test]
+    fn test_from_toml() {
+        let scenario = Scenario::from_toml(
+            r#"
+name = "split brain"
+
+[[steps]]
+at = "0s"
+shape = { shape = "majority_minority", seed = 7 }
+
+[[steps]]
+at = "1m"
+restore = {}
+"#,
+        )
+        .unwrap();
+        assert_eq!(
+            scenario.steps[0].action,
+            StepAction::Shape {
+                shape: Shape::MajorityMinority { minority: None },
+                seed: Some(7),
+                action: Action::Drop,
+            }
+        );
+        assert_eq!(scenario.steps[1].at, Duration::from_secs(60));
+    }
+
+    #[test]
+    fn test_out_of_order() {
+        assert!(Scenario::from_yaml(
+            "
+steps:
+  - { at: 10s, restore: {} }
+  - { at: 5s, restore: {} }
+"
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_resolve_peer() {
+        let supervisor = Supervisor::new(vec![
+            Peer::new("192.168.192.2".parse().unwrap(), None, None),
+            Peer::new("192.168.192.3".parse().unwrap(), None, None),
+        ]);
+        let ids = supervisor.get_peer_ids().to_vec();
+        assert_eq!(PeerRef::Index(1).resolve(&supervisor).unwrap(), ids[1]);
+        assert_eq!(
+            PeerRef::Name("192.168.192.2".into())
+                .resolve(&supervisor)
+                .unwrap(),
+            ids[0]
+        );
+        assert_eq!(
+            PeerRef::Name(ids[1].to_string())
+                .resolve(&supervisor)
+                .unwrap(),
+            ids[1]
+        );
+        assert!(PeerRef::Index(2).resolve(&supervisor).is_err());
+        assert!(PeerRef::Name("leader".into()).resolve(&supervisor).is_err());
+    }
+}
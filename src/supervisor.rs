@@ -1,18 +1,48 @@
 use openssh::Session;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::env::var;
+use std::net::IpAddr;
 use std::process::Output;
+use std::time::{Duration, SystemTime};
+use tokio::sync::broadcast;
 use tokio::sync::mpsc::Receiver;
 use uuid::Uuid;
 
-use crate::commands::{Command, Commands, SshCommands};
+use crate::commands::{
+    Command, Commands, Direction, IpTablesCommands, Netem, SshCommands, TcCommands,
+};
+use crate::drift::{Drift, DriftCheck};
+use crate::events::{ClusterEvent, ClusterEventKind, Event, EventLog};
+use crate::faults::{Fault, FaultKind, FaultRegistry, Partition};
 use crate::peer::Peer;
+use crate::rules::{parse_rules, Rule};
+use crate::topology::{Shape, Topology};
 
 #[derive(Debug, Default)]
 pub struct Supervisor {
     peers: HashMap<Uuid, Peer>,
     peer_ids: Vec<Uuid>,
     path_to_key: String,
+    snapshots: HashMap<String, Snapshot>,
+    faults: FaultRegistry,
+    /// Where to record every command run on the peers, if anywhere.
+    event_log: Option<EventLog>,
+    /// Where to announce the faults as they get applied and healed, if anywhere.
+    cluster_events: Option<broadcast::Sender<ClusterEvent>>,
+}
+
+/// The firewall rules of every peer, captured at some point so that they can be reapplied later.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub name: String,
+    /// The rules of each peer, as dumped by `IpTablesCommands::Save`.
+    pub rules: HashMap<Uuid, String>,
+    /// The partitions the supervisor had applied when the snapshot was taken.
+    #[serde(default)]
+    pub faults: Vec<Fault>,
 }
 
 pub type Message = (Uuid, Commands);
@@ -33,6 +63,28 @@ impl Supervisor {
             .ok_or(crate::Error::PeerNotFound(peer_id))
     }
 
+    /// The id of the peer with the given address, if any.
+    pub fn find_peer_by_ip(&self, ip_addr: IpAddr) -> Option<Uuid> {
+        self.peer_ids
+            .iter()
+            .copied()
+            .find(|peer_id| self.peers[peer_id].ip_addr == ip_addr)
+    }
+
+    /// The address of the peer, along with the htb class the other peers shape the traffic
+    /// towards it in. Every peer gets its own class from its index, after the 2 classes
+    /// `tc` keeps for itself.
+    fn tc_destination(&self, peer_id: Uuid) -> crate::Result<(IpAddr, u16)> {
+        let ip_addr = self.get_peer(peer_id)?.ip_addr;
+        let class = self
+            .peer_ids
+            .iter()
+            .position(|id| *id == peer_id)
+            .and_then(|index| u16::try_from(index + 2).ok())
+            .ok_or(crate::Error::PeerNotFound(peer_id))?;
+        Ok((ip_addr, class))
+    }
+
     fn copy_id(&self, peer_id: Uuid) -> crate::Result<()> {
         let peer = self.peers.get(&peer_id).unwrap();
         let mut command = SshCommands::CopyId {
@@ -64,7 +116,10 @@ impl Supervisor {
         Ok(())
     }
 
-    pub fn new(peers: Vec<Peer>) -> Self {
+    /// Supervise the peers, ordered by address. Consul answers with the peers in a
+    /// different order every time, so this keeps their indices stable across reloads.
+    pub fn new(mut peers: Vec<Peer>) -> Self {
+        peers.sort_by_key(|peer| peer.ip_addr);
         let peer_ids = peers.iter().map(|peer| peer.id).collect::<Vec<_>>();
 
         let hmap = peers
@@ -79,6 +134,10 @@ impl Supervisor {
             peers: hmap,
             peer_ids,
             path_to_key,
+            snapshots: HashMap::new(),
+            faults: FaultRegistry::default(),
+            event_log: None,
+            cluster_events: None,
         }
     }
 
@@ -87,6 +146,50 @@ impl Supervisor {
         self
     }
 
+    /// Record every command run on the peers into the given log.
+    pub fn with_event_log(mut self, event_log: EventLog) -> Self {
+        self.event_log = Some(event_log);
+        self
+    }
+
+    /// Take over the faults of the supervisor this one replaces, so that they still expire
+    /// and get healed. Their peers are matched by address; the faults of the peers that
+    /// are gone get dropped.
+    pub fn with_faults_of(mut self, previous: &Supervisor) -> Self {
+        let peer_id = |previous_id| {
+            let ip_addr = previous.get_peer(previous_id).ok()?.ip_addr;
+            self.find_peer_by_ip(ip_addr)
+        };
+        let mut faults = vec![];
+        for fault in previous.faults() {
+            match (peer_id(fault.source), peer_id(fault.target)) {
+                (Some(source), Some(target)) => faults.push(Fault {
+                    source,
+                    target,
+                    ..fault.clone()
+                }),
+                _ => tracing::warn!("Dropping the fault {:?}, its peers are gone.", fault),
+            }
+        }
+        for fault in faults {
+            self.faults.record(fault);
+        }
+        self
+    }
+
+    /// Announce the faults on the given channel as they get applied and healed.
+    pub fn with_cluster_events(mut self, cluster_events: broadcast::Sender<ClusterEvent>) -> Self {
+        self.cluster_events = Some(cluster_events);
+        self
+    }
+
+    /// Announce something that happened to the cluster. It's fine if nobody's listening.
+    pub fn notify(&self, kind: ClusterEventKind) {
+        if let Some(cluster_events) = &self.cluster_events {
+            let _ = cluster_events.send(kind.into());
+        }
+    }
+
     pub async fn connect(&mut self, peer_id: Uuid) -> crate::Result<()> {
         if let Some(peer) = self.peers.get_mut(&peer_id) {
             peer.connect().await?;
@@ -106,18 +209,763 @@ impl Supervisor {
     fn get_session(&self, peer_id: Uuid) -> crate::Result<&Session> {
         self.peers
             .get(&peer_id)
-            .and_then(|peer| peer.session.as_ref())
+            .and_then(|peer| peer.session.as_deref())
             .ok_or(crate::errors::PartitionSimError::SessionUninitialized)
     }
 
+    /// Translate the firewall commands into the ones for the firewall the peer actually has.
+    fn for_peer(&self, peer_id: Uuid, command: Commands) -> crate::Result<Commands> {
+        Ok(self.get_peer(peer_id)?.translate(command))
+    }
+
     pub async fn execute(
         &mut self,
         peer_id: Uuid,
         command: impl Into<Commands>,
     ) -> crate::Result<Output> {
+        let command = command.into();
         self.connect(peer_id).await?;
         let session = self.get_session(peer_id)?;
-        Ok(command.into().build(session).output().await?)
+        let output = self
+            .for_peer(peer_id, command.clone())?
+            .build(session)
+            .output()
+            .await;
+        if self.event_log.is_some() {
+            let cluster = self
+                .peer_ids
+                .iter()
+                .map(|peer_id| Ok(self.get_peer(*peer_id)?.ip_addr))
+                .collect::<crate::Result<Vec<_>>>()?;
+            let event = Event::new(
+                peer_id,
+                self.get_peer(peer_id)?.ip_addr,
+                cluster,
+                command,
+                &output,
+            );
+            if let Some(Err(err)) = self.event_log.as_mut().map(|log| log.record(&event)) {
+                tracing::warn!("Couldn't record {:?}: {}", event, err);
+            }
+        }
+        if output.is_err() {
+            // The connection may have died with the peer (e.g. when its container restarted),
+            // so reconnect the next time around.
+            self.get_peer_mut(peer_id)?.session = None;
+        }
+        Ok(output?)
+    }
+
+    /// List our firewall rules on a peer, with their addresses resolved to peers where possible.
+    pub async fn get_rules(&mut self, peer_id: Uuid) -> crate::Result<Vec<Rule>> {
+        let output = self.execute(peer_id, IpTablesCommands::Get).await?;
+        if !output.status.success() {
+            return Err(output.into());
+        }
+        let mut rules = parse_rules(&String::from_utf8_lossy(&output.stdout))?;
+        for rule in rules.iter_mut() {
+            rule.resolve_peers(|ip| self.find_peer_by_ip(ip));
+        }
+        Ok(rules)
+    }
+
+    /// Work out the reachability between every pair of peers from the rules on each of them.
+    pub async fn topology(&mut self) -> crate::Result<Topology> {
+        let mut rules = HashMap::new();
+        for peer_id in self.peer_ids.clone() {
+            rules.insert(peer_id, self.get_rules(peer_id).await?);
+        }
+        Ok(Topology::from_rules(&self.peer_ids, &rules))
+    }
+
+    /// Capture the firewall rules of every peer into a snapshot with the given name,
+    /// replacing any previous snapshot with that name.
+    pub async fn take_snapshot(&mut self, name: &str) -> crate::Result<&Snapshot> {
+        let mut rules = HashMap::new();
+        for peer_id in self.peer_ids.clone() {
+            let output = self.execute(peer_id, IpTablesCommands::Save).await?;
+            if !output.status.success() {
+                return Err(output.into());
+            }
+            rules.insert(peer_id, String::from_utf8_lossy(&output.stdout).to_string());
+        }
+        self.snapshots.insert(
+            name.to_string(),
+            Snapshot {
+                name: name.to_string(),
+                rules,
+                faults: self.faults.partitions(),
+            },
+        );
+        self.get_snapshot(name)
+    }
+
+    pub fn get_snapshot(&self, name: &str) -> crate::Result<&Snapshot> {
+        self.snapshots
+            .get(name)
+            .ok_or_else(|| crate::Error::SnapshotNotFound(name.to_string()))
+    }
+
+    /// Replace the firewall rules of every peer in the snapshot with the captured ones.
+    pub async fn apply_snapshot(&mut self, name: &str) -> crate::Result<()> {
+        let snapshot = self.get_snapshot(name)?.clone();
+        for (peer_id, rules) in snapshot.rules {
+            let output = self
+                .execute(peer_id, IpTablesCommands::Load { rules })
+                .await?;
+            if !output.status.success() {
+                return Err(output.into());
+            }
+        }
+        self.faults.replace_partitions(snapshot.faults);
+        self.notify(ClusterEventKind::SnapshotApplied {
+            name: name.to_string(),
+        });
+        Ok(())
+    }
+
+    /// Like `execute`, but fails if the command exits with a bad status code.
+    async fn execute_checked(
+        &mut self,
+        peer_id: Uuid,
+        command: impl Into<Commands>,
+    ) -> crate::Result<Output> {
+        let output = self.execute(peer_id, command).await?;
+        if !output.status.success() {
+            return Err(output.into());
+        }
+        Ok(output)
+    }
+
+    /// Run each of the firewall commands on the peer (in a single round trip), stopping at
+    /// the first one that fails, and count the rules they changed.
+    async fn execute_all(
+        &mut self,
+        peer_id: Uuid,
+        commands: Vec<IpTablesCommands>,
+    ) -> crate::Result<usize> {
+        if commands.is_empty() {
+            return Ok(0);
+        }
+        let output = self
+            .execute_checked(peer_id, IpTablesCommands::Batch(commands))
+            .await?;
+        IpTablesCommands::rules_changed(&output.stdout)
+    }
+
+    /// The faults currently applied, in the order they were applied.
+    pub fn faults(&self) -> &[Fault] {
+        self.faults.list()
+    }
+
+    /// Ask the target peer to cut the traffic from/to the source peer, and record the partition.
+    /// Returns whether any rule was added.
+    pub async fn partition(
+        &mut self,
+        source_peer_id: Uuid,
+        target_peer_id: Uuid,
+        partition: Partition,
+        ttl: Option<Duration>,
+    ) -> crate::Result<bool> {
+        partition.validate()?;
+        let ip_addr = self.get_peer(source_peer_id)?.ip_addr;
+        let rules_changed = self
+            .execute_all(target_peer_id, partition.drop_commands(ip_addr))
+            .await?;
+        self.faults
+            .add_partition(source_peer_id, target_peer_id, partition, ttl);
+        self.notify(ClusterEventKind::Partition {
+            source: source_peer_id,
+            target: target_peer_id,
+            partition,
+        });
+        Ok(rules_changed > 0)
+    }
+
+    /// Ask the target peer to accept the traffic from/to the source peer again, and forget
+    /// the partition. Without a partition, every partition recorded between the peers gets
+    /// healed (or the default one, if none is). Returns whether any rule was deleted.
+    pub async fn heal(
+        &mut self,
+        source_peer_id: Uuid,
+        target_peer_id: Uuid,
+        partition: Option<Partition>,
+    ) -> crate::Result<bool> {
+        let partitions = match partition {
+            Some(partition) => vec![partition],
+            None => self
+                .recorded_partitions(|source, target| {
+                    source == source_peer_id && target == target_peer_id
+                })
+                .into_iter()
+                .map(|(_, _, partition)| partition)
+                .collect(),
+        };
+        if partitions.is_empty() {
+            return self
+                .heal_one(source_peer_id, target_peer_id, Partition::default())
+                .await;
+        }
+        let mut changed = false;
+        for partition in partitions {
+            changed |= self
+                .heal_one(source_peer_id, target_peer_id, partition)
+                .await?;
+        }
+        Ok(changed)
+    }
+
+    /// The partitions recorded between the pairs of peers (source first) that match.
+    fn recorded_partitions(
+        &self,
+        matches: impl Fn(Uuid, Uuid) -> bool,
+    ) -> Vec<(Uuid, Uuid, Partition)> {
+        self.faults
+            .list()
+            .iter()
+            .filter_map(|fault| match fault.kind {
+                FaultKind::Partition(partition) if matches(fault.source, fault.target) => {
+                    Some((fault.source, fault.target, partition))
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    async fn heal_one(
+        &mut self,
+        source_peer_id: Uuid,
+        target_peer_id: Uuid,
+        partition: Partition,
+    ) -> crate::Result<bool> {
+        partition.validate()?;
+        let ip_addr = self.get_peer(source_peer_id)?.ip_addr;
+        let rules_changed = self
+            .execute_all(target_peer_id, partition.restore_commands(ip_addr))
+            .await?;
+        self.faults
+            .heal_partition(source_peer_id, target_peer_id, &partition);
+        self.notify(ClusterEventKind::Heal {
+            source: source_peer_id,
+            target: target_peer_id,
+            partition,
+        });
+        Ok(rules_changed > 0)
+    }
+
+    /// Every pair of peers from different groups, the one from the earlier group first.
+    /// Each peer may only be in one group, and there must be at least two groups.
+    pub fn cross_group_pairs(&self, groups: &[Vec<Uuid>]) -> crate::Result<Vec<(Uuid, Uuid)>> {
+        if groups.len() < 2 || groups.iter().any(|group| group.is_empty()) {
+            return Err(crate::Error::InvalidParameter(
+                "expected at least two non-empty groups".into(),
+            ));
+        }
+        let mut seen = std::collections::HashSet::new();
+        for &peer_id in groups.iter().flatten() {
+            self.get_peer(peer_id)?;
+            if !seen.insert(peer_id) {
+                return Err(crate::Error::InvalidParameter(format!(
+                    "peer {} is in more than one group",
+                    peer_id
+                )));
+            }
+        }
+        let mut pairs = vec![];
+        for (index, group) in groups.iter().enumerate() {
+            for later_group in &groups[index + 1..] {
+                for &peer_id in group {
+                    for &other_peer_id in later_group {
+                        pairs.push((peer_id, other_peer_id));
+                    }
+                }
+            }
+        }
+        Ok(pairs)
+    }
+
+    /// Run the commands built for each pair of peers on the second peer of the pair,
+    /// with a single round trip per peer, and count the rules they changed. Each pair is
+    /// handed to `done` as soon as its peer ran the commands, so that the peers done before
+    /// a failure still get accounted for.
+    async fn execute_for_pairs(
+        &mut self,
+        pairs: &[(Uuid, Uuid)],
+        commands: impl Fn(IpAddr) -> Vec<IpTablesCommands>,
+        mut done: impl FnMut(&mut Self, Uuid, Uuid),
+    ) -> crate::Result<usize> {
+        let mut batches: Vec<(Uuid, Vec<Uuid>, Vec<IpTablesCommands>)> = vec![];
+        for &(source_peer_id, target_peer_id) in pairs {
+            let ip_addr = self.get_peer(source_peer_id)?.ip_addr;
+            match batches
+                .iter_mut()
+                .find(|(peer_id, _, _)| *peer_id == target_peer_id)
+            {
+                Some((_, sources, peer_commands)) => {
+                    sources.push(source_peer_id);
+                    peer_commands.extend(commands(ip_addr));
+                }
+                None => batches.push((target_peer_id, vec![source_peer_id], commands(ip_addr))),
+            }
+        }
+        let mut rules_changed = 0;
+        for (peer_id, sources, commands) in batches {
+            rules_changed += self.execute_all(peer_id, commands).await?;
+            for source_peer_id in sources {
+                done(self, source_peer_id, peer_id);
+            }
+        }
+        Ok(rules_changed)
+    }
+
+    /// Cut every link between peers of different groups, both ways, while keeping the links
+    /// within each group intact. Each pair is cut on the peer from the later group.
+    /// Returns whether any rule was added.
+    pub async fn partition_groups(
+        &mut self,
+        groups: &[Vec<Uuid>],
+        partition: Partition,
+        ttl: Option<Duration>,
+    ) -> crate::Result<bool> {
+        let pairs = self.cross_group_pairs(groups)?;
+        self.partition_pairs(&pairs, partition, ttl).await
+    }
+
+    /// Cut the link between each pair of peers, both ways, on the second peer of the pair.
+    /// Returns whether any rule was added.
+    pub async fn partition_pairs(
+        &mut self,
+        pairs: &[(Uuid, Uuid)],
+        partition: Partition,
+        ttl: Option<Duration>,
+    ) -> crate::Result<bool> {
+        let partition = Partition {
+            direction: Direction::Both,
+            ..partition
+        };
+        partition.validate()?;
+        let rules_changed = self
+            .execute_for_pairs(
+                pairs,
+                |ip_addr| partition.drop_commands(ip_addr),
+                |supervisor, source_peer_id, target_peer_id| {
+                    supervisor
+                        .faults
+                        .add_partition(source_peer_id, target_peer_id, partition, ttl);
+                    supervisor.notify(ClusterEventKind::Partition {
+                        source: source_peer_id,
+                        target: target_peer_id,
+                        partition,
+                    });
+                },
+            )
+            .await?;
+        Ok(rules_changed > 0)
+    }
+
+    /// Give the cluster a well-known partition shape, and return the pairs of peers
+    /// whose links got cut. The `seed` makes the random choices repeatable.
+    pub async fn apply_shape(
+        &mut self,
+        shape: &Shape,
+        partition: Partition,
+        seed: Option<u64>,
+        ttl: Option<Duration>,
+    ) -> crate::Result<Vec<(Uuid, Uuid)>> {
+        let mut rng = match seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+        let cuts = shape.cuts(&self.peer_ids, &mut rng)?;
+        self.partition_pairs(&cuts, partition, ttl).await?;
+        Ok(cuts)
+    }
+
+    /// Heal every link cut by `partition_groups` with the same groups and partition.
+    /// Returns whether any rule was deleted.
+    pub async fn heal_groups(
+        &mut self,
+        groups: &[Vec<Uuid>],
+        partition: Partition,
+    ) -> crate::Result<bool> {
+        let partition = Partition {
+            direction: Direction::Both,
+            ..partition
+        };
+        partition.validate()?;
+        let pairs = self.cross_group_pairs(groups)?;
+        let rules_changed = self
+            .execute_for_pairs(
+                &pairs,
+                |ip_addr| partition.restore_commands(ip_addr),
+                |supervisor, source_peer_id, target_peer_id| {
+                    supervisor
+                        .faults
+                        .heal_partition(source_peer_id, target_peer_id, &partition);
+                    supervisor.notify(ClusterEventKind::Heal {
+                        source: source_peer_id,
+                        target: target_peer_id,
+                        partition,
+                    });
+                },
+            )
+            .await?;
+        Ok(rules_changed > 0)
+    }
+
+    /// The groups that cut the peer off from every other peer. The peer goes last so
+    /// that all of the rules end up on it, in a single round trip.
+    fn isolation_groups(&self, peer_id: Uuid) -> crate::Result<Vec<Vec<Uuid>>> {
+        self.get_peer(peer_id)?;
+        let others: Vec<Uuid> = self
+            .peer_ids
+            .iter()
+            .copied()
+            .filter(|other_peer_id| *other_peer_id != peer_id)
+            .collect();
+        if others.is_empty() {
+            return Err(crate::Error::InvalidParameter(format!(
+                "there are no other peers to isolate {} from",
+                peer_id
+            )));
+        }
+        Ok(vec![others, vec![peer_id]])
+    }
+
+    /// Cut the peer off from every other peer, both ways. Returns whether any rule was added.
+    pub async fn isolate(
+        &mut self,
+        peer_id: Uuid,
+        partition: Partition,
+        ttl: Option<Duration>,
+    ) -> crate::Result<bool> {
+        let groups = self.isolation_groups(peer_id)?;
+        self.partition_groups(&groups, partition, ttl).await
+    }
+
+    /// Undo `isolate` (with the same partition). Without a partition, every partition
+    /// recorded between the peer and the others gets healed (or the default isolation, if
+    /// none is). Returns whether any rule was deleted.
+    pub async fn rejoin(
+        &mut self,
+        peer_id: Uuid,
+        partition: Option<Partition>,
+    ) -> crate::Result<bool> {
+        let groups = self.isolation_groups(peer_id)?;
+        let recorded = match partition {
+            Some(partition) => return self.heal_groups(&groups, partition).await,
+            None => self.partitions_of(peer_id),
+        };
+        if recorded.is_empty() {
+            return self.heal_groups(&groups, Partition::default()).await;
+        }
+        let mut changed = false;
+        for (source_peer_id, target_peer_id, partition) in recorded {
+            changed |= self
+                .heal_one(source_peer_id, target_peer_id, partition)
+                .await?;
+        }
+        Ok(changed)
+    }
+
+    /// Ask the target peer to forget the connections it tracks from/to the source peer
+    /// in the direction(s) of the partition.
+    pub async fn flush_conntrack(
+        &mut self,
+        source_peer_id: Uuid,
+        target_peer_id: Uuid,
+        partition: Partition,
+    ) -> crate::Result<()> {
+        let ip_addr = self.get_peer(source_peer_id)?.ip_addr;
+        for command in partition.conntrack_commands(ip_addr) {
+            let output = self.execute_checked(target_peer_id, command).await?;
+            tracing::debug!(
+                "Flushed conntrack entries on {0}: {1}",
+                target_peer_id,
+                String::from_utf8_lossy(&output.stdout).trim()
+            );
+        }
+        Ok(())
+    }
+
+    /// Ask the target peer to impair the traffic it sends to the source peer, replacing
+    /// any impairments set before.
+    pub async fn netem(
+        &mut self,
+        source_peer_id: Uuid,
+        target_peer_id: Uuid,
+        netem: Netem,
+        ttl: Option<Duration>,
+    ) -> crate::Result<()> {
+        netem.validate()?;
+        let (destination_ip, class) = self.tc_destination(source_peer_id)?;
+        self.execute_checked(
+            target_peer_id,
+            TcCommands::NetemTo {
+                destination_ip,
+                class,
+                netem: netem.clone(),
+            },
+        )
+        .await?;
+        self.faults.set_shaping(
+            source_peer_id,
+            target_peer_id,
+            FaultKind::Netem(netem.clone()),
+            ttl,
+        );
+        self.notify(ClusterEventKind::Netem {
+            source: source_peer_id,
+            target: target_peer_id,
+            netem,
+        });
+        Ok(())
+    }
+
+    /// Ask the target peer to stop impairing the traffic it sends to the source peer.
+    pub async fn clear_netem(
+        &mut self,
+        source_peer_id: Uuid,
+        target_peer_id: Uuid,
+    ) -> crate::Result<()> {
+        let (destination_ip, class) = self.tc_destination(source_peer_id)?;
+        self.execute_checked(
+            target_peer_id,
+            TcCommands::ClearNetemTo {
+                destination_ip,
+                class,
+            },
+        )
+        .await?;
+        self.faults.clear_shaping(
+            source_peer_id,
+            target_peer_id,
+            &FaultKind::Netem(Netem::default()),
+        );
+        self.notify(ClusterEventKind::ClearNetem {
+            source: source_peer_id,
+            target: target_peer_id,
+        });
+        Ok(())
+    }
+
+    /// Ask the target peer to cap the rate at which it sends traffic to the source peer.
+    pub async fn throttle(
+        &mut self,
+        source_peer_id: Uuid,
+        target_peer_id: Uuid,
+        rate_kbit: u32,
+        ttl: Option<Duration>,
+    ) -> crate::Result<()> {
+        if rate_kbit == 0 {
+            return Err(crate::Error::InvalidParameter(
+                "rate_kbit must be positive".into(),
+            ));
+        }
+        let (destination_ip, class) = self.tc_destination(source_peer_id)?;
+        self.execute_checked(
+            target_peer_id,
+            TcCommands::ThrottleTo {
+                destination_ip,
+                class,
+                rate_kbit,
+            },
+        )
+        .await?;
+        self.faults.set_shaping(
+            source_peer_id,
+            target_peer_id,
+            FaultKind::Throttle { rate_kbit },
+            ttl,
+        );
+        self.notify(ClusterEventKind::Throttle {
+            source: source_peer_id,
+            target: target_peer_id,
+            rate_kbit,
+        });
+        Ok(())
+    }
+
+    /// Ask the target peer to stop capping the rate at which it sends traffic to the source peer.
+    pub async fn clear_throttle(
+        &mut self,
+        source_peer_id: Uuid,
+        target_peer_id: Uuid,
+    ) -> crate::Result<()> {
+        let (destination_ip, class) = self.tc_destination(source_peer_id)?;
+        self.execute_checked(
+            target_peer_id,
+            TcCommands::ClearThrottleTo {
+                destination_ip,
+                class,
+            },
+        )
+        .await?;
+        self.faults.clear_shaping(
+            source_peer_id,
+            target_peer_id,
+            &FaultKind::Throttle { rate_kbit: 0 },
+        );
+        self.notify(ClusterEventKind::ClearThrottle {
+            source: source_peer_id,
+            target: target_peer_id,
+        });
+        Ok(())
+    }
+
+    /// The partitions recorded between the peer and any other peer, either way.
+    fn partitions_of(&self, peer_id: Uuid) -> Vec<(Uuid, Uuid, Partition)> {
+        self.recorded_partitions(|source, target| source == peer_id || target == peer_id)
+    }
+
+    /// The partitions applied on the peer (as its target).
+    fn partitions_on(&self, peer_id: Uuid) -> Vec<(Uuid, Partition)> {
+        self.recorded_partitions(|_, target| target == peer_id)
+            .into_iter()
+            .map(|(source, _, partition)| (source, partition))
+            .collect()
+    }
+
+    /// The rules the peer should have, given the partitions applied on it.
+    fn desired_rules(&self, peer_id: Uuid) -> crate::Result<Vec<Rule>> {
+        let mut rules = vec![];
+        for (source_peer_id, partition) in self.partitions_on(peer_id) {
+            let ip_addr = self.get_peer(source_peer_id)?.ip_addr;
+            for command in partition.drop_commands(ip_addr) {
+                if let Some(spec) = command.rule_spec() {
+                    let mut rule = Rule::from_spec(&spec)?;
+                    rule.resolve_peers(|ip| self.find_peer_by_ip(ip));
+                    rules.push(rule);
+                }
+            }
+        }
+        Ok(rules)
+    }
+
+    /// Compare the rules every peer actually has with the ones the applied faults call for,
+    /// and return the peers that drifted.
+    pub async fn check_drift(&mut self) -> crate::Result<Vec<Drift>> {
+        let mut drifts = vec![];
+        for peer_id in self.peer_ids.clone() {
+            let drift = self.drift_of(peer_id).await?;
+            if !drift.is_empty() {
+                drifts.push(drift);
+            }
+        }
+        Ok(drifts)
+    }
+
+    async fn drift_of(&mut self, peer_id: Uuid) -> crate::Result<Drift> {
+        let actual = self.get_rules(peer_id).await?;
+        Ok(Drift::between(
+            peer_id,
+            &self.desired_rules(peer_id)?,
+            &actual,
+        ))
+    }
+
+    /// Capture what it takes to check every peer for drift, so that the checks can run
+    /// without holding on to the supervisor.
+    pub fn drift_checks(&self) -> crate::Result<Vec<DriftCheck>> {
+        let peer_ids = self
+            .peers
+            .values()
+            .map(|peer| (peer.ip_addr, peer.id))
+            .collect::<HashMap<_, _>>();
+        self.peer_ids
+            .iter()
+            .map(|peer_id| {
+                Ok(DriftCheck::new(
+                    self.get_peer(*peer_id)?.clone(),
+                    self.desired_rules(*peer_id)?,
+                    peer_ids.clone(),
+                ))
+            })
+            .collect()
+    }
+
+    /// Check for drift and put back the rules the peers lost by reapplying their partitions.
+    /// Unexpected rules are only reported, since they may have been added on purpose.
+    /// Returns the drift found before the repair.
+    pub async fn reconcile(&mut self) -> crate::Result<Vec<Drift>> {
+        let mut drifts = vec![];
+        for peer_id in self.peer_ids.clone() {
+            let drift = self.reconcile_peer(peer_id).await?;
+            if !drift.is_empty() {
+                drifts.push(drift);
+            }
+        }
+        Ok(drifts)
+    }
+
+    /// Like `reconcile`, for a single peer.
+    pub async fn reconcile_peer(&mut self, peer_id: Uuid) -> crate::Result<Drift> {
+        let drift = self.drift_of(peer_id).await?;
+        if !drift.missing.is_empty() {
+            for (source_peer_id, partition) in self.partitions_on(peer_id) {
+                let ip_addr = self.get_peer(source_peer_id)?.ip_addr;
+                self.execute_all(peer_id, partition.drop_commands(ip_addr))
+                    .await?;
+            }
+        }
+        Ok(drift)
+    }
+
+    /// Heal every fault whose time is up. Each fault gets healed on its own, so one
+    /// unreachable peer doesn't keep the others broken. Returns the healed faults, and
+    /// the ones that couldn't be healed along with why; those stay recorded, so that
+    /// the next call retries them.
+    pub async fn heal_expired(&mut self) -> (Vec<Fault>, Vec<(Fault, crate::Error)>) {
+        let mut healed = vec![];
+        let mut failed = vec![];
+        for fault in self.faults.expired(SystemTime::now()) {
+            let result = match &fault.kind {
+                FaultKind::Partition(_) => self.heal_expired_partition(&fault).await,
+                FaultKind::Netem(_) => self.clear_netem(fault.source, fault.target).await,
+                FaultKind::Throttle { .. } => self.clear_throttle(fault.source, fault.target).await,
+            };
+            match result {
+                Ok(()) => healed.push(fault),
+                Err(err) => {
+                    tracing::warn!("Couldn't heal the expired fault {:?}: {}", fault, err);
+                    self.notify(ClusterEventKind::Error {
+                        message: format!("couldn't heal the expired fault {}: {}", fault.id, err),
+                    });
+                    failed.push((fault, err));
+                }
+            }
+        }
+        (healed, failed)
+    }
+
+    /// Heal the expired partition, but leave the rules the other partitions between
+    /// the peers still need.
+    async fn heal_expired_partition(&mut self, fault: &Fault) -> crate::Result<()> {
+        if let Some(partition) = self.faults.uncovered(fault) {
+            partition.validate()?;
+            let ip_addr = self.get_peer(fault.source)?.ip_addr;
+            self.execute_all(fault.target, partition.restore_commands(ip_addr))
+                .await?;
+            self.notify(ClusterEventKind::Heal {
+                source: fault.source,
+                target: fault.target,
+                partition,
+            });
+        }
+        self.faults.remove(fault.id);
+        Ok(())
+    }
+
+    /// Delete all the firewall rules and traffic shaping we added on every peer,
+    /// and forget all the faults.
+    pub async fn restore(&mut self) -> crate::Result<()> {
+        for peer_id in self.peer_ids.clone() {
+            self.execute(peer_id, IpTablesCommands::Restore).await?;
+            self.execute(peer_id, TcCommands::Restore).await?;
+        }
+        self.faults.clear();
+        self.notify(ClusterEventKind::Restore);
+        Ok(())
     }
 
     pub async fn run(
@@ -127,6 +975,7 @@ impl Supervisor {
         while let Some((msg, result_tx)) = commands_rx.recv().await {
             let (peer_id, command) = msg;
             self.connect(peer_id).await?;
+            let command = self.for_peer(peer_id, command)?;
             let session = self.get_session(peer_id)?;
             let output = command.build(session).output().await?;
             result_tx.send(output)?;
@@ -139,6 +988,7 @@ impl Supervisor {
 mod tests {
     use super::*;
 
+    use crate::commands::Action;
     use std::env;
     use tokio::sync::mpsc::channel;
 
@@ -168,7 +1018,7 @@ mod tests {
         let t1 = tokio::spawn(async move {
             tx.send((
                 (
-                    *peer_ids.get(0).unwrap(),
+                    *peer_ids.first().unwrap(),
                     Commands::IpTables(crate::commands::IpTablesCommands::Get),
                 ),
                 request_tx,
@@ -183,4 +1033,273 @@ mod tests {
         let t3 = supervisor.run(rx);
         let _ = tokio::join!(t1, t2, t3);
     }
+
+    #[test]
+    fn test_cross_group_pairs() {
+        let peers = (1..=5)
+            .map(|i| Peer::new(format!("192.168.192.{}", i).parse().unwrap(), None, None))
+            .collect();
+        let supervisor = Supervisor::new(peers);
+        let ids = supervisor.get_peer_ids().to_vec();
+
+        let pairs = supervisor
+            .cross_group_pairs(&[ids[..2].to_vec(), ids[2..].to_vec()])
+            .unwrap();
+        assert_eq!(pairs.len(), 6);
+        assert!(pairs.contains(&(ids[0], ids[4])));
+        assert!(!pairs.contains(&(ids[0], ids[1])));
+
+        assert!(supervisor.cross_group_pairs(&[ids[..].to_vec()]).is_err());
+        assert!(supervisor
+            .cross_group_pairs(&[ids[..2].to_vec(), ids[1..].to_vec()])
+            .is_err());
+        assert!(supervisor
+            .cross_group_pairs(&[ids[..2].to_vec(), vec![Uuid::new_v4()]])
+            .is_err());
+    }
+
+    #[test]
+    fn test_isolation_groups() {
+        let peers = (1..=3)
+            .map(|i| Peer::new(format!("192.168.192.{}", i).parse().unwrap(), None, None))
+            .collect();
+        let supervisor = Supervisor::new(peers);
+        let ids = supervisor.get_peer_ids().to_vec();
+
+        let groups = supervisor.isolation_groups(ids[1]).unwrap();
+        assert_eq!(groups, vec![vec![ids[0], ids[2]], vec![ids[1]]]);
+        // All the rules go on the isolated peer.
+        assert!(supervisor
+            .cross_group_pairs(&groups)
+            .unwrap()
+            .iter()
+            .all(|(_, target_peer_id)| *target_peer_id == ids[1]));
+
+        let lonely = Supervisor::new(vec![Peer::new(
+            "192.168.192.1".parse().unwrap(),
+            None,
+            None,
+        )]);
+        assert!(lonely.isolation_groups(lonely.get_peer_ids()[0]).is_err());
+    }
+
+    #[test]
+    fn test_peers_ordered_by_address() {
+        let peers = [
+            "192.168.192.10",
+            "fd00::2",
+            "192.168.192.9",
+            "192.168.192.2",
+        ]
+        .iter()
+        .map(|ip| Peer::new(ip.parse().unwrap(), None, None))
+        .collect();
+        let supervisor = Supervisor::new(peers);
+        let ips = supervisor
+            .get_peer_ids()
+            .iter()
+            .map(|peer_id| supervisor.get_peer(*peer_id).unwrap().ip_addr.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            ips,
+            [
+                "192.168.192.2",
+                "192.168.192.9",
+                "192.168.192.10",
+                "fd00::2"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_tc_classes_are_distinct() {
+        // Their last octets used to map onto the same class.
+        let peers = ["10.0.0.1", "10.0.0.2", "10.0.1.2", "fd00::2"]
+            .iter()
+            .map(|ip| Peer::new(ip.parse().unwrap(), None, None))
+            .collect();
+        let supervisor = Supervisor::new(peers);
+        let mut classes = supervisor
+            .get_peer_ids()
+            .iter()
+            .map(|peer_id| supervisor.tc_destination(*peer_id).unwrap().1)
+            .collect::<Vec<_>>();
+        assert!(classes.iter().all(|class| *class >= 2));
+        classes.dedup();
+        assert_eq!(classes.len(), 4);
+        assert!(supervisor.tc_destination(Uuid::new_v4()).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_heal_expired_keeps_going() {
+        let mut supervisor = Supervisor::default();
+        // Neither peer is loaded, so healing fails for both faults.
+        for _ in 0..2 {
+            supervisor.faults.add_partition(
+                Uuid::new_v4(),
+                Uuid::new_v4(),
+                Partition::default(),
+                Some(Duration::ZERO),
+            );
+        }
+        let (healed, failed) = supervisor.heal_expired().await;
+        assert!(healed.is_empty());
+        assert_eq!(failed.len(), 2);
+        // They stay recorded, so that the next sweep retries them.
+        assert_eq!(supervisor.faults().len(), 2);
+    }
+
+    #[test]
+    fn test_reject_partition_rules() {
+        let peers = ["10.0.0.1", "10.0.0.2"]
+            .iter()
+            .map(|ip| Peer::new(ip.parse().unwrap(), None, None))
+            .collect();
+        let mut supervisor = Supervisor::new(peers);
+        let (source, target) = (supervisor.peer_ids[0], supervisor.peer_ids[1]);
+        supervisor.faults.add_partition(
+            source,
+            target,
+            Partition {
+                direction: Direction::Both,
+                action: Action::Reject,
+                ..Default::default()
+            },
+            None,
+        );
+        let rules = supervisor.desired_rules(target).unwrap();
+        assert_eq!(rules.len(), 2);
+        assert!(rules.iter().all(|rule| rule.target == "REJECT"));
+        assert_eq!(rules[0].source_peer, Some(source));
+        assert_eq!(rules[1].destination_peer, Some(source));
+        assert!(supervisor.desired_rules(source).unwrap().is_empty());
+
+        let commands = supervisor.partitions_on(target)[0]
+            .1
+            .drop_commands("10.0.0.1".parse().unwrap());
+        assert!(commands[0]
+            .script()
+            .contains("-s 10.0.0.1 -j REJECT --reject-with icmp-port-unreachable"));
+        assert!(commands[1]
+            .script()
+            .contains("-d 10.0.0.1 -j REJECT --reject-with icmp-port-unreachable"));
+    }
+
+    #[tokio::test]
+    async fn test_heal_expired_keeps_overlapping_partitions() {
+        let (source, target) = (Uuid::new_v4(), Uuid::new_v4());
+        let mut supervisor = Supervisor::default();
+        let both = Partition {
+            direction: Direction::Both,
+            ..Default::default()
+        };
+        supervisor.faults.add_partition(source, target, both, None);
+        supervisor
+            .faults
+            .add_partition(source, target, Partition::default(), Some(Duration::ZERO));
+        // Nothing needs to run on the (unknown) peers, since the permanent partition
+        // still needs every rule of the expired one.
+        let (healed, failed) = supervisor.heal_expired().await;
+        assert_eq!(healed.len(), 1);
+        assert!(failed.is_empty());
+        assert_eq!(supervisor.faults().len(), 1);
+        assert_eq!(supervisor.faults()[0].kind, FaultKind::Partition(both));
+    }
+
+    #[test]
+    fn test_faults_carried_over() {
+        let load = |ips: &[&str]| {
+            Supervisor::new(
+                ips.iter()
+                    .map(|ip| Peer::new(ip.parse().unwrap(), None, None))
+                    .collect(),
+            )
+        };
+        let mut previous = load(&["10.0.0.1", "10.0.0.2", "10.0.0.3"]);
+        let ids = previous.get_peer_ids().to_vec();
+        let ttl = Some(Duration::from_secs(30));
+        previous
+            .faults
+            .add_partition(ids[0], ids[1], Partition::default(), ttl);
+        previous
+            .faults
+            .add_partition(ids[2], ids[1], Partition::default(), None);
+
+        let supervisor = load(&["10.0.0.2", "10.0.0.1"]).with_faults_of(&previous);
+        assert_eq!(supervisor.faults().len(), 1);
+        let fault = &supervisor.faults()[0];
+        assert_eq!(
+            supervisor
+                .get_peer(fault.source)
+                .unwrap()
+                .ip_addr
+                .to_string(),
+            "10.0.0.1"
+        );
+        assert_eq!(
+            supervisor
+                .get_peer(fault.target)
+                .unwrap()
+                .ip_addr
+                .to_string(),
+            "10.0.0.2"
+        );
+        assert_eq!(fault.expires_at, previous.faults()[0].expires_at);
+    }
+
+    #[tokio::test]
+    async fn test_execute_for_pairs_accounts_for_the_peers_done() {
+        let peers = ["10.0.0.1", "10.0.0.2"]
+            .iter()
+            .map(|ip| Peer::new(ip.parse().unwrap(), None, None))
+            .collect();
+        let mut supervisor = Supervisor::new(peers);
+        let (first, second) = (supervisor.peer_ids[0], supervisor.peer_ids[1]);
+        let gone = Uuid::new_v4();
+        // Nothing to run on the second peer, so its batch succeeds without connecting,
+        // while the batch on the unknown peer fails.
+        let first_ip = supervisor.get_peer(first).unwrap().ip_addr;
+        let mut done = vec![];
+        let result = supervisor
+            .execute_for_pairs(
+                &[(first, second), (second, gone)],
+                |ip_addr| {
+                    if ip_addr == first_ip {
+                        vec![]
+                    } else {
+                        Partition::default().drop_commands(ip_addr)
+                    }
+                },
+                |_, source, target| done.push((source, target)),
+            )
+            .await;
+        assert!(result.is_err());
+        assert_eq!(done, [(first, second)]);
+    }
+
+    #[test]
+    fn test_rejoin_heals_the_recorded_partitions() {
+        let (peer, other, another) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let mut supervisor = Supervisor::default();
+        let isolation = Partition {
+            direction: Direction::Both,
+            action: Action::Reject,
+            ..Default::default()
+        };
+        supervisor
+            .faults
+            .add_partition(other, peer, isolation, None);
+        supervisor
+            .faults
+            .add_partition(another, peer, isolation, None);
+        supervisor
+            .faults
+            .add_partition(other, another, Partition::default(), None);
+        // Rejoining without an action undoes the rejecting isolation, not the default one.
+        assert_eq!(
+            supervisor.partitions_of(peer),
+            [(other, peer, isolation), (another, peer, isolation)]
+        );
+        assert!(supervisor.partitions_of(Uuid::new_v4()).is_empty());
+    }
 }
@@ -0,0 +1,300 @@
+use std::collections::HashMap;
+
+use rand::seq::SliceRandom;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::rules::Rule;
+
+/// How well packets get from one peer to another.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Reachability {
+    /// No rule gets in the way.
+    Reachable,
+    /// Some packets get through, because the rules in the way only apply to
+    /// some protocols or ports, or only to a share of the packets.
+    Partial,
+    /// A rule drops (or rejects) every packet.
+    Unreachable,
+}
+
+/// The reachability from one peer to another.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Link {
+    pub from: Uuid,
+    pub to: Uuid,
+    pub reachability: Reachability,
+    /// The rules (on either peer) that get in the way.
+    pub rules: Vec<Rule>,
+}
+
+/// The reachability between every ordered pair of peers in the cluster.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Topology {
+    pub peers: Vec<Uuid>,
+    pub links: Vec<Link>,
+}
+
+impl Rule {
+    /// Whether the rule gets in the way of every packet it could apply to.
+    fn blocks_everything(&self) -> bool {
+        self.protocol.is_none() && self.ports.is_none() && self.probability.is_none()
+    }
+}
+
+impl Topology {
+    /// Work out the topology from the (resolved) rules on each peer. A rule on a peer
+    /// affects packets from its `source_peer` to it, or from it to its `destination_peer`.
+    pub fn from_rules(peer_ids: &[Uuid], rules: &HashMap<Uuid, Vec<Rule>>) -> Self {
+        let mut links = vec![];
+        for &from in peer_ids {
+            for &to in peer_ids {
+                if from == to {
+                    continue;
+                }
+                let inbound = rules
+                    .get(&to)
+                    .into_iter()
+                    .flatten()
+                    .filter(|rule| rule.source_peer == Some(from));
+                let outbound = rules
+                    .get(&from)
+                    .into_iter()
+                    .flatten()
+                    .filter(|rule| rule.destination_peer == Some(to));
+                let rules: Vec<Rule> = inbound.chain(outbound).cloned().collect();
+                let reachability = if rules.iter().any(Rule::blocks_everything) {
+                    Reachability::Unreachable
+                } else if rules.is_empty() {
+                    Reachability::Reachable
+                } else {
+                    Reachability::Partial
+                };
+                links.push(Link {
+                    from,
+                    to,
+                    reachability,
+                    rules,
+                });
+            }
+        }
+        Self {
+            peers: peer_ids.to_vec(),
+            links,
+        }
+    }
+}
+
+/// A well-known partition shape, as found in the RaftMonkey paper and in Jepsen's nemeses.
+/// Each shape boils down to a set of links to cut both ways, which depends on the peers
+/// (and, for some shapes, on chance).
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "shape", rename_all = "snake_case")]
+pub enum Shape {
+    /// Split the peers into two random halves.
+    RandomHalves,
+    /// Split the peers into a majority and a minority, with the given peer (e.g. the leader)
+    /// or a random one in the minority.
+    MajorityMinority { minority: Option<Uuid> },
+    /// Split the other peers into two random halves that can't talk to each other, while the
+    /// given peer (or a random one) bridges them by seeing both sides.
+    Bridge { bridge: Option<Uuid> },
+    /// Arrange the peers in a random ring where each peer only sees its two neighbours.
+    Ring,
+    /// Only let the peers talk to the given peer (or a random one) at the center. Pick a
+    /// center other than the leader to cut the leader off from everyone but the center.
+    Star { center: Option<Uuid> },
+}
+
+/// Every pair of peers from different sides, the one from the first side first.
+fn across(side: &[Uuid], other_side: &[Uuid]) -> Vec<(Uuid, Uuid)> {
+    side.iter()
+        .flat_map(|&peer_id| other_side.iter().map(move |&other| (peer_id, other)))
+        .collect()
+}
+
+/// The given peer, if it is one of the peers, or a random one.
+fn pick(peer_ids: &[Uuid], peer_id: Option<Uuid>, rng: &mut impl Rng) -> crate::Result<Uuid> {
+    match peer_id {
+        Some(peer_id) if peer_ids.contains(&peer_id) => Ok(peer_id),
+        Some(peer_id) => Err(crate::Error::PeerNotFound(peer_id)),
+        None => peer_ids
+            .choose(rng)
+            .copied()
+            .ok_or_else(|| crate::Error::InvalidParameter("there are no peers".into())),
+    }
+}
+
+impl Shape {
+    /// The pairs of peers whose links get cut (both ways) to give the peers this shape.
+    pub fn cuts(&self, peer_ids: &[Uuid], rng: &mut impl Rng) -> crate::Result<Vec<(Uuid, Uuid)>> {
+        let mut shuffled = peer_ids.to_vec();
+        shuffled.shuffle(rng);
+        let cuts = match self {
+            Self::RandomHalves => {
+                let (half, other_half) = shuffled.split_at(shuffled.len() / 2);
+                across(half, other_half)
+            }
+            Self::MajorityMinority { minority } => {
+                let member = pick(peer_ids, *minority, rng)?;
+                // The largest minority there is, which takes at least three peers.
+                let minority_size = (peer_ids.len().max(1) - 1) / 2;
+                if minority_size == 0 {
+                    return Err(crate::Error::InvalidParameter(format!(
+                        "a majority and a minority take at least 3 peers, got {}",
+                        peer_ids.len()
+                    )));
+                }
+                shuffled.retain(|&peer_id| peer_id != member);
+                let mut minority = shuffled.split_off(shuffled.len() - (minority_size - 1));
+                minority.push(member);
+                across(&shuffled, &minority)
+            }
+            Self::Bridge { bridge } => {
+                let bridge = pick(peer_ids, *bridge, rng)?;
+                shuffled.retain(|&peer_id| peer_id != bridge);
+                let (half, other_half) = shuffled.split_at(shuffled.len() / 2);
+                across(half, other_half)
+            }
+            Self::Ring => {
+                let count = shuffled.len();
+                let mut cuts = vec![];
+                for i in 0..count {
+                    for j in i + 1..count {
+                        let neighbours = j - i == 1 || (i == 0 && j == count - 1);
+                        if !neighbours {
+                            cuts.push((shuffled[i], shuffled[j]));
+                        }
+                    }
+                }
+                cuts
+            }
+            Self::Star { center } => {
+                let center = pick(peer_ids, *center, rng)?;
+                shuffled.retain(|&peer_id| peer_id != center);
+                let mut cuts = vec![];
+                for (i, &peer_id) in shuffled.iter().enumerate() {
+                    for &other in &shuffled[i + 1..] {
+                        cuts.push((peer_id, other));
+                    }
+                }
+                cuts
+            }
+        };
+        if cuts.is_empty() {
+            return Err(crate::Error::InvalidParameter(format!(
+                "there are too few peers ({}) for {:?} to cut anything",
+                peer_ids.len(),
+                self
+            )));
+        }
+        Ok(cuts)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_rules() {
+        let peer_ids = [Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4()];
+        let mut rules = HashMap::new();
+        // Peer 1 drops everything from peer 0.
+        rules.insert(
+            peer_ids[1],
+            vec![Rule {
+                target: "DROP".into(),
+                source_peer: Some(peer_ids[0]),
+                ..Default::default()
+            }],
+        );
+        // Peer 2 drops a share of what it sends to peer 0.
+        rules.insert(
+            peer_ids[2],
+            vec![Rule {
+                target: "DROP".into(),
+                destination_peer: Some(peer_ids[0]),
+                probability: Some(0.5),
+                ..Default::default()
+            }],
+        );
+
+        let topology = Topology::from_rules(&peer_ids, &rules);
+        assert_eq!(topology.links.len(), 6);
+        let reachability = |from: usize, to: usize| {
+            topology
+                .links
+                .iter()
+                .find(|link| link.from == peer_ids[from] && link.to == peer_ids[to])
+                .unwrap()
+                .reachability
+        };
+        assert_eq!(reachability(0, 1), Reachability::Unreachable);
+        assert_eq!(reachability(1, 0), Reachability::Reachable);
+        assert_eq!(reachability(2, 0), Reachability::Partial);
+        assert_eq!(reachability(1, 2), Reachability::Reachable);
+    }
+
+    fn sides(peer_ids: &[Uuid], cuts: &[(Uuid, Uuid)]) -> Vec<usize> {
+        // How many peers each peer got cut off from.
+        peer_ids
+            .iter()
+            .map(|peer_id| {
+                cuts.iter()
+                    .filter(|(a, b)| a == peer_id || b == peer_id)
+                    .count()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_shape_cuts() {
+        let mut rng = rand::rngs::mock::StepRng::new(0, 1);
+        let peer_ids: Vec<Uuid> = (0..5).map(|_| Uuid::new_v4()).collect();
+
+        let cuts = Shape::RandomHalves.cuts(&peer_ids, &mut rng).unwrap();
+        assert_eq!(cuts.len(), 6);
+
+        let leader = peer_ids[3];
+        let cuts = Shape::MajorityMinority {
+            minority: Some(leader),
+        }
+        .cuts(&peer_ids, &mut rng)
+        .unwrap();
+        assert_eq!(cuts.len(), 6);
+        assert!(cuts.iter().filter(|(_, b)| *b == leader).count() == 3);
+
+        let cuts = Shape::Bridge {
+            bridge: Some(leader),
+        }
+        .cuts(&peer_ids, &mut rng)
+        .unwrap();
+        assert_eq!(cuts.len(), 4);
+        assert!(cuts.iter().all(|(a, b)| *a != leader && *b != leader));
+
+        // Every peer keeps its two neighbours.
+        let cuts = Shape::Ring.cuts(&peer_ids, &mut rng).unwrap();
+        assert_eq!(sides(&peer_ids, &cuts), vec![2; 5]);
+
+        let cuts = Shape::Star {
+            center: Some(leader),
+        }
+        .cuts(&peer_ids, &mut rng)
+        .unwrap();
+        assert_eq!(cuts.len(), 6);
+        assert!(cuts.iter().all(|(a, b)| *a != leader && *b != leader));
+
+        assert!(Shape::Ring.cuts(&peer_ids[..3], &mut rng).is_err());
+        assert!(Shape::MajorityMinority { minority: None }
+            .cuts(&peer_ids[..2], &mut rng)
+            .is_err());
+        assert!(Shape::Star {
+            center: Some(Uuid::new_v4())
+        }
+        .cuts(&peer_ids, &mut rng)
+        .is_err());
+    }
+}